@@ -2,18 +2,30 @@ use std::borrow::Cow;
 use std::iter::FromIterator;
 use std::{cell::RefCell, collections::HashMap};
 
-use candid::CandidType;
+use candid::{CandidType, Func, Principal};
 use ic_cdk::{
     api::{self, call},
     export::candid,
 };
-use ic_certified_map::{AsHashTree, Hash, RbTree};
+use ic_certified_map::{AsHashTree, Hash, HashTree, RbTree};
 use percent_encoding::percent_decode_str;
 use serde::{Deserialize, Serialize};
 use serde_cbor::Serializer;
 use sha2::{Digest, Sha256};
 
-use crate::{MetadataPurpose, MetadataVal, STATE};
+use crate::stable::Fixed;
+use crate::{
+    LogoResult, MetadataPart, MetadataPurpose, MetadataVal, State, DEFAULT_LOGO, HTTP_HASHES, STATE,
+};
+
+const BURNED: &[u8] = b"This NFT has been burned";
+const NO_METADATA: &[u8] = b"No metadata for this NFT";
+// Gateways check the response for a path that isn't in the tree against the hash at /index.html,
+// so every such path serves the same response, which is certified there.
+const NOT_FOUND: &[u8] = b"Not found";
+const NOT_FOUND_PATH: &str = "/index.html";
+// Parts bigger than this are streamed, and each of their chunks can also be fetched on its own.
+const CHUNK_SIZE: usize = 1_500_000; // leaves room for the headers in a 2 MiB reply
 
 #[derive(CandidType, Deserialize)]
 struct HttpRequest {
@@ -28,6 +40,201 @@ struct HttpResponse<'a> {
     status_code: u16,
     headers: HashMap<&'a str, Cow<'a, str>>,
     body: Cow<'a, [u8]>,
+    streaming_strategy: Option<StreamingStrategy>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct StreamingToken {
+    token_id: u64,
+    part: u64,
+    chunk: u64, // the one to send next
+}
+
+#[derive(CandidType)]
+enum StreamingStrategy {
+    Callback {
+        callback: Func,
+        token: StreamingToken,
+    },
+}
+
+#[derive(CandidType)]
+struct StreamingCallbackHttpResponse {
+    body: Vec<u8>,
+    token: Option<StreamingToken>,
+    // an IC-Certificate header value for the chunk, certified at /<nft>/<id>/<chunk>
+    certificate: String,
+}
+
+fn chunk_count(len: usize) -> usize {
+    (len + CHUNK_SIZE - 1) / CHUNK_SIZE
+}
+
+fn chunk(data: &[u8], index: usize) -> &[u8] {
+    let start = (index * CHUNK_SIZE).min(data.len());
+    &data[start..data.len().min(start + CHUNK_SIZE)]
+}
+
+// default metadata: first non-preview metadata, or if there is none, first metadata
+fn default_part(metadata: &[MetadataPart]) -> Option<usize> {
+    metadata
+        .iter()
+        .position(|x| x.purpose == MetadataPurpose::Rendered)
+        .or_else(|| if metadata.is_empty() { None } else { Some(0) })
+}
+
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+// The tests run outside of a canister, which has neither an id nor certified data.
+#[cfg(not(test))]
+fn canister_id() -> Principal {
+    api::id()
+}
+
+#[cfg(test)]
+fn canister_id() -> Principal {
+    Principal::from_slice(&[0xff])
+}
+
+#[cfg(not(test))]
+fn set_certified_data(data: &[u8]) {
+    api::set_certified_data(data);
+}
+
+#[cfg(test)]
+fn set_certified_data(_: &[u8]) {}
+
+#[cfg(not(test))]
+fn data_certificate() -> Vec<u8> {
+    api::data_certificate().unwrap()
+}
+
+#[cfg(test)]
+fn data_certificate() -> Vec<u8> {
+    vec![]
+}
+
+// where the gateway serves `path` from this canister
+fn asset_url(path: &str) -> String {
+    if cfg!(mainnet) {
+        format!("https://{}.ic0.app{}", canister_id(), path)
+    } else {
+        format!("http://localhost:8000{}?canisterId={}", path, canister_id())
+    }
+}
+
+// An OpenSea-style description of the token, from the key-value data of its default part. The
+// keys other than the ones below become attributes, except for blobs, which have no JSON form.
+// The document has to come out the same every time for its hash to hold, so they are sorted.
+fn metadata_json(token_id: u64, owner: &Principal, metadata: &[MetadataPart]) -> String {
+    let empty = HashMap::new();
+    let data = default_part(metadata).map_or(&empty, |part| &metadata[part].key_val_data);
+    let text = |key: &str| match data.get(key) {
+        Some(MetadataVal::TextContent(text)) => Some(json_string(text)),
+        _ => None,
+    };
+    let mut fields = vec![];
+    if let Some(name) = text("name") {
+        fields.push(format!("\"name\":{}", name));
+    }
+    if let Some(description) = text("description") {
+        fields.push(format!("\"description\":{}", description));
+    }
+    let image =
+        text("location").unwrap_or_else(|| json_string(&asset_url(&format!("/{}", token_id))));
+    fields.push(format!("\"image\":{}", image));
+    if let Some(content_type) = text("contentType") {
+        fields.push(format!("\"content_type\":{}", content_type));
+    }
+    fields.push(format!("\"owner\":{}", json_string(&owner.to_text())));
+    fields.push(format!("\"token_id\":{}", token_id));
+    let mut keys: Vec<_> = data
+        .keys()
+        .filter(|key| {
+            ![
+                "name",
+                "description",
+                "location",
+                "locationType",
+                "contentType",
+            ]
+            .contains(&key.as_str())
+        })
+        .collect();
+    keys.sort();
+    let attributes: Vec<_> = keys
+        .into_iter()
+        .filter_map(|key| {
+            let value = match &data[key] {
+                MetadataVal::TextContent(text) => json_string(text),
+                MetadataVal::BlobContent(_) => return None,
+                MetadataVal::NatContent(n) => n.to_string(),
+                MetadataVal::Nat8Content(n) => n.to_string(),
+                MetadataVal::Nat16Content(n) => n.to_string(),
+                MetadataVal::Nat32Content(n) => n.to_string(),
+                MetadataVal::Nat64Content(n) => n.to_string(),
+            };
+            Some(format!(
+                "{{\"trait_type\":{},\"value\":{}}}",
+                json_string(key),
+                value
+            ))
+        })
+        .collect();
+    fields.push(format!("\"attributes\":[{}]", attributes.join(",")));
+    format!("{{{}}}", fields.join(","))
+}
+
+// the image itself; a logo that isn't valid base64 is served empty
+fn logo_bytes(logo: &LogoResult) -> Vec<u8> {
+    base64::decode(logo.data.as_bytes()).unwrap_or_default()
+}
+
+fn collection_json(state: &State) -> String {
+    format!(
+        "{{\"name\":{},\"symbol\":{},\"image\":{},\"total_supply\":{}}}",
+        json_string(&state.name),
+        json_string(&state.symbol),
+        json_string(&asset_url("/logo")),
+        state.supply()
+    )
+}
+
+// the body of a response serving the whole part, which is only its first chunk if it is streamed
+fn first_chunk(
+    token_id: u64,
+    part: usize,
+    mut data: Vec<u8>,
+) -> (Cow<'static, [u8]>, Option<StreamingStrategy>) {
+    if data.len() <= CHUNK_SIZE {
+        return (data.into(), None);
+    }
+    data.truncate(CHUNK_SIZE);
+    let strategy = StreamingStrategy::Callback {
+        callback: Func {
+            principal: canister_id(),
+            method: "http_request_streaming_callback".to_string(),
+        },
+        token: StreamingToken {
+            token_id,
+            part: part as u64,
+            chunk: 1,
+        },
+    };
+    (data.into(), Some(strategy))
 }
 
 // This could reply with a lot of data. To return this data from the function would require it to be cloned,
@@ -46,96 +253,186 @@ fn http_request(/* req: HttpRequest */) /* -> HttpResponse */
     STATE.with(|state| {
         let state = state.borrow();
         let url = req.url.split('?').next().unwrap_or("/");
-        let cert = format!(
-            "certificate=:{}:, tree=:{}:",
-            base64::encode(api::data_certificate().unwrap()),
-            witness(&url)
-        )
-        .into();
-        let mut path = url[1..].split('/')
-            .map(|segment| percent_decode_str(segment).decode_utf8().unwrap());
-        let mut headers = HashMap::from_iter([
-            (
-                "Content-Security-Policy",
-                "default-src 'self' ; script-src 'none' ; frame-src 'none' ; object-src 'none'"
-                    .into(),
-            ),
-            ("IC-Certificate", cert),
-        ]);
+        let mut response = respond(&state, url);
+        let headers = &mut response.headers;
+        headers.insert(
+            "Content-Security-Policy",
+            "default-src 'self' ; script-src 'none' ; frame-src 'none' ; object-src 'none'".into(),
+        );
+        headers.insert("IC-Certificate", certificate(url).into());
         if cfg!(mainnet) {
             headers.insert(
                 "Strict-Transport-Security",
                 "max-age=31536000; includeSubDomains".into(),
             );
         }
-        let root = path.next().unwrap_or_else(|| "".into());
-        let body;
-        let mut code = 200;
-        if root == "" {
-            body = format!("Total NFTs: {}", state.nfts.len())
-                .into_bytes()
-                .into();
-        } else {
-            if let Ok(num) = root.parse::<usize>() {
-                // /:something
-                if let Some(nft) = state.nfts.get(num) {
+        call::reply((response,));
+    });
+}
+
+// The response for `url`, but for the headers that are the same for every path.
+fn respond(state: &State, url: &str) -> HttpResponse<'static> {
+    let mut headers = HashMap::new();
+    if HASHES.with(|hashes| hashes.borrow().get(url.as_bytes()).is_none()) {
+        // including other spellings of real paths, like with a trailing slash
+        return HttpResponse {
+            status_code: 404,
+            headers,
+            body: NOT_FOUND.into(),
+            streaming_strategy: None,
+        };
+    }
+    let mut path = url[1..]
+        .split('/')
+        .map(|segment| percent_decode_str(segment).decode_utf8().unwrap());
+    let root = path.next().unwrap_or_else(|| "".into());
+    let body;
+    let mut code = 200;
+    let mut streaming_strategy = None;
+    if root == "" {
+        body = format!("Total NFTs: {}", state.supply())
+            .into_bytes()
+            .into();
+    } else if root == "logo" {
+        // /logo
+        let logo = state.logo.as_ref().unwrap_or(&DEFAULT_LOGO);
+        headers.insert("Content-Type", logo.logo_type.clone());
+        body = logo_bytes(logo).into();
+    } else if root == "collection.json" {
+        // /collection.json
+        headers.insert("Content-Type", "application/json".into());
+        body = collection_json(state).into_bytes().into();
+    } else if root == "index.html" {
+        // /index.html
+        code = 404;
+        body = NOT_FOUND.into();
+    } else {
+        if let Ok(num) = root.parse::<u64>() {
+            // /:something
+            if let Ok(nft) = state.nft(num) {
+                if nft.burned {
+                    // /:nft and everything under it, certified as such once burned
+                    code = 410;
+                    body = BURNED.into();
+                } else {
                     // /:nft
+                    // the metadata is decoded from stable memory, so the parts are owned and can be moved into the body
+                    let mut metadata = nft.metadata();
                     let img = path.next().unwrap_or_else(|| "".into());
                     if img == "" {
                         // /:nft/
-                        let part = nft
-                            .metadata
-                            .iter()
-                            .find(|x| x.purpose == MetadataPurpose::Rendered)
-                            .or_else(|| nft.metadata.get(0));
-                        if let Some(part) = part {
-                            // default metadata: first non-preview metadata, or if there is none, first metadata
-                            body = part.data.as_slice().into();
+                        if let Some(index) = default_part(&metadata) {
+                            let part = metadata.swap_remove(index);
                             if let Some(MetadataVal::TextContent(mime)) =
                                 part.key_val_data.get("contentType")
                             {
-                                headers.insert("Content-Type", mime.as_str().into());
+                                headers.insert("Content-Type", mime.clone().into());
                             }
+                            let (first, strategy) = first_chunk(num, index, part.data);
+                            body = first;
+                            streaming_strategy = strategy;
                         } else {
                             // no metadata to be found
-                            body = b"No metadata for this NFT"[..].into();
+                            body = NO_METADATA.into();
                         }
+                    } else if img == "metadata.json" {
+                        // /:nft/metadata.json
+                        headers.insert("Content-Type", "application/json".into());
+                        body = metadata_json(num, &nft.owner, &metadata)
+                            .into_bytes()
+                            .into();
                     } else {
                         // /:nft/:something
-                        if let Ok(num) = img.parse::<usize>() {
+                        if let Ok(index) = img.parse::<usize>() {
                             // /:nft/:number
-                            if let Some(part) = nft.metadata.get(num) {
+                            if index < metadata.len() {
                                 // /:nft/:id
-                                body = part.data.as_slice().into();
+                                let part = metadata.swap_remove(index);
                                 if let Some(MetadataVal::TextContent(mime)) =
                                     part.key_val_data.get("contentType")
                                 {
-                                    headers.insert("Content-Type", mime.as_str().into());
+                                    headers.insert("Content-Type", mime.clone().into());
+                                }
+                                let chunks = chunk_count(part.data.len());
+                                let sub = path.next().unwrap_or_else(|| "".into());
+                                if sub == "" {
+                                    let (first, strategy) = first_chunk(num, index, part.data);
+                                    body = first;
+                                    streaming_strategy = strategy;
+                                } else {
+                                    // /:nft/:id/:something
+                                    match sub.parse::<usize>() {
+                                        // /:nft/:id/:chunk, only for streamed parts
+                                        Ok(i) if chunks > 1 && i < chunks => {
+                                            body = chunk(&part.data, i).to_vec().into();
+                                        }
+                                        _ => {
+                                            code = 404;
+                                            body = NOT_FOUND.into();
+                                        }
+                                    }
                                 }
                             } else {
                                 code = 404;
-                                body = b"No such metadata part"[..].into();
+                                body = NOT_FOUND.into();
                             }
                         } else {
-                            code = 400;
-                            body = format!("Invalid metadata ID {}", img).into_bytes().into();
+                            code = 404;
+                            body = NOT_FOUND.into();
                         }
                     }
-                } else {
-                    code = 404;
-                    body = b"No such NFT"[..].into();
                 }
             } else {
-                code = 400;
-                body = format!("Invalid NFT ID {}", root).into_bytes().into();
+                code = 404;
+                body = NOT_FOUND.into();
             }
+        } else {
+            code = 404;
+            body = NOT_FOUND.into();
         }
-        call::reply((HttpResponse {
-            status_code: code,
-            headers,
-            body,
-        },));
-    });
+    }
+    HttpResponse {
+        status_code: code,
+        headers,
+        body,
+        streaming_strategy,
+    }
+}
+
+// The gateway checks the assembled body against the hash certified for the whole part, and each
+// chunk comes with the certificate for its own path, for clients that check it as it arrives.
+#[query]
+fn http_request_streaming_callback(token: StreamingToken) -> StreamingCallbackHttpResponse {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let data = match state.nft(token.token_id) {
+            Ok(nft) if !nft.burned => nft
+                .metadata()
+                .into_iter()
+                .nth(token.part as usize)
+                .map(|part| part.data),
+            _ => None,
+        };
+        let data = data.unwrap_or_else(|| api::trap("No such NFT or metadata part"));
+        let chunks = chunk_count(data.len()) as u64;
+        if token.chunk >= chunks {
+            api::trap("No such chunk");
+        }
+        let next = token.chunk + 1;
+        let path = format!("/{}/{}/{}", token.token_id, token.part, token.chunk);
+        StreamingCallbackHttpResponse {
+            body: chunk(&data, token.chunk as usize).to_vec(),
+            token: if next < chunks {
+                Some(StreamingToken {
+                    chunk: next,
+                    ..token
+                })
+            } else {
+                None
+            },
+            certificate: certificate(&path),
+        }
+    })
 }
 
 thread_local! {
@@ -143,37 +440,191 @@ thread_local! {
     pub static HASHES: RefCell<RbTree<String, Hash>> = RefCell::new(RbTree::from_iter([("/".to_string(), *b"\x83\xd0\xf6\x70\x86\x5c\x36\x7c\xe9\x5f\x59\x59\x59\xab\xec\x46\xed\x7b\x64\x03\x3e\xce\xe9\xed\x77\x2e\x78\x79\x3f\x3b\xc1\x0f")]));
 }
 
+// The RbTree is what gets witnessed, but it lives on the heap, so every hash is also kept in
+// HTTP_HASHES and the tree is rebuilt from there after an upgrade.
+pub struct HashPath(pub String);
+
+impl Fixed for HashPath {
+    // a length byte followed by the path
+    const SIZE: u64 = 64;
+    fn write_to(&self, buf: &mut [u8]) {
+        let bytes = self.0.as_bytes();
+        if bytes.len() >= Self::SIZE as usize {
+            api::trap(&format!("HTTP path {} is too long", self.0));
+        }
+        buf[0] = bytes.len() as u8;
+        buf[1..=bytes.len()].copy_from_slice(bytes);
+    }
+    fn read_from(buf: &[u8]) -> Self {
+        let len = buf[0] as usize;
+        HashPath(String::from_utf8_lossy(&buf[1..=len]).into_owned())
+    }
+}
+
+fn insert_hash(hashes: &mut RbTree<String, Hash>, path: String, hash: Hash) {
+    HTTP_HASHES.insert(&HashPath(path.clone()), &hash);
+    hashes.insert(path, hash);
+}
+
+fn certify(hashes: &RbTree<String, Hash>) {
+    let cert = ic_certified_map::labeled_hash(b"http_assets", &hashes.root_hash());
+    set_certified_data(&cert);
+}
+
+// after a token is minted or burned
 pub fn add_hash(tkid: u64) {
     crate::STATE.with(|state| {
         HASHES.with(|hashes| {
             let state = state.borrow();
             let mut hashes = hashes.borrow_mut();
-            let nft = state.nfts.get(tkid as usize)?;
-            let mut default = false;
-            for (i, metadata) in nft.metadata.iter().enumerate() {
-                let hash = Sha256::digest(&metadata.data);
-                hashes.insert(format!("/{}/{}", tkid, i), hash.into());
-                if !default && matches!(metadata.purpose, MetadataPurpose::Rendered) {
-                    default = true;
-                    hashes.insert(format!("/{}", tkid), hash.into());
+            let nft = state.nft(tkid).ok()?;
+            if nft.burned {
+                // every path of a burned token serves the same 410 response
+                let hash: Hash = Sha256::digest(BURNED).into();
+                for i in 0..nft.metadata().len() {
+                    // the data may have been purged already, so the chunks are found in the tree
+                    let mut c = 0;
+                    while hashes
+                        .get(format!("/{}/{}/{}", tkid, i, c).as_bytes())
+                        .is_some()
+                    {
+                        insert_hash(&mut hashes, format!("/{}/{}/{}", tkid, i, c), hash);
+                        c += 1;
+                    }
+                    insert_hash(&mut hashes, format!("/{}/{}", tkid, i), hash);
+                }
+                insert_hash(&mut hashes, format!("/{}", tkid), hash);
+                insert_hash(&mut hashes, format!("/{}/metadata.json", tkid), hash);
+            } else {
+                let metadata = nft.metadata();
+                let default = match default_part(&metadata) {
+                    Some(index) => Sha256::digest(&metadata[index].data),
+                    None => Sha256::digest(NO_METADATA),
+                };
+                insert_hash(&mut hashes, format!("/{}", tkid), default.into());
+                for (i, metadata) in metadata.iter().enumerate() {
+                    let hash = Sha256::digest(&metadata.data);
+                    insert_hash(&mut hashes, format!("/{}/{}", tkid, i), hash.into());
+                    let chunks = chunk_count(metadata.data.len());
+                    if chunks > 1 {
+                        for c in 0..chunks {
+                            let hash = Sha256::digest(chunk(&metadata.data, c));
+                            insert_hash(&mut hashes, format!("/{}/{}/{}", tkid, i, c), hash.into());
+                        }
+                    }
                 }
+                insert_json_hash(&mut hashes, tkid, &nft.owner, &metadata);
             }
-            hashes.insert(
-                "/".to_string(),
-                Sha256::digest(format!("Total NFTs: {}", state.nfts.len())).into(),
-            );
-            let cert = ic_certified_map::labeled_hash(b"http_assets", &hashes.root_hash());
-            api::set_certified_data(&cert);
+            insert_collection_hashes(&mut hashes, &state);
+            certify(&hashes);
             Some(())
         })
     });
 }
 
+// Bumped whenever a version certifies paths its predecessors didn't, so that the first upgrade to
+// it hashes what is missing and the upgrades after it don't have to look.
+pub const HASHES_VERSION: u32 = 1;
+
+// Versions before this one only certified /<nft> for tokens with a rendered part.
+pub fn rehash_uncertified(token_count: u64) {
+    let missing: Vec<_> = HASHES.with(|hashes| {
+        let hashes = hashes.borrow();
+        (0..token_count)
+            .filter(|token_id| hashes.get(format!("/{}", token_id).as_bytes()).is_none())
+            .collect()
+    });
+    for token_id in missing {
+        add_hash(token_id);
+    }
+}
+
+fn insert_json_hash(
+    hashes: &mut RbTree<String, Hash>,
+    token_id: u64,
+    owner: &Principal,
+    metadata: &[MetadataPart],
+) {
+    let json = metadata_json(token_id, owner, metadata);
+    insert_hash(
+        hashes,
+        format!("/{}/metadata.json", token_id),
+        Sha256::digest(json).into(),
+    );
+}
+
+// the paths that don't belong to a single token
+fn insert_collection_hashes(hashes: &mut RbTree<String, Hash>, state: &State) {
+    insert_hash(
+        hashes,
+        "/".to_string(),
+        Sha256::digest(format!("Total NFTs: {}", state.supply())).into(),
+    );
+    let logo = state.logo.as_ref().unwrap_or(&DEFAULT_LOGO);
+    insert_hash(
+        hashes,
+        "/logo".to_string(),
+        Sha256::digest(logo_bytes(logo)).into(),
+    );
+    let json = collection_json(state);
+    insert_hash(
+        hashes,
+        "/collection.json".to_string(),
+        Sha256::digest(json).into(),
+    );
+    insert_hash(
+        hashes,
+        NOT_FOUND_PATH.to_string(),
+        Sha256::digest(NOT_FOUND).into(),
+    );
+}
+
+// after init, an upgrade, or a change to the collection's name, symbol or logo; takes the state,
+// since the callers have it borrowed already
+pub(crate) fn update_collection(state: &State) {
+    HASHES.with(|hashes| {
+        let mut hashes = hashes.borrow_mut();
+        insert_collection_hashes(&mut hashes, state);
+        certify(&hashes);
+    });
+}
+
+// after a token changes hands, since its metadata.json names the owner; takes the state, as the
+// caller is in the middle of changing it
+pub(crate) fn update_owner(state: &State, token_id: u64) {
+    HASHES.with(|hashes| {
+        let mut hashes = hashes.borrow_mut();
+        if let Ok(nft) = state.nft(token_id) {
+            insert_json_hash(&mut hashes, token_id, &nft.owner, &nft.metadata());
+            certify(&hashes);
+        }
+    });
+}
+
+// Only the hashes are read back, never the content they were computed from.
+pub fn restore_hashes() {
+    HASHES.with(|hashes| {
+        let mut hashes = hashes.borrow_mut();
+        for (HashPath(path), hash) in HTTP_HASHES.iter() {
+            hashes.insert(path, hash);
+        }
+        certify(&hashes);
+    });
+}
+
+// the value of the IC-Certificate header for `name`
+fn certificate(name: &str) -> String {
+    format!(
+        "certificate=:{}:, tree=:{}:",
+        base64::encode(data_certificate()),
+        witness(name)
+    )
+}
+
 fn witness(name: &str) -> String {
     HASHES.with(|hashes| {
         let hashes = hashes.borrow();
-        let witness = hashes.witness(name.as_bytes());
-        let tree = ic_certified_map::labeled(b"http_assets", witness);
+        let tree = ic_certified_map::labeled(b"http_assets", path_witness(&hashes, name));
         let mut data = vec![];
         let mut serializer = Serializer::new(&mut data);
         serializer.self_describe().unwrap();
@@ -181,3 +632,210 @@ fn witness(name: &str) -> String {
         base64::encode(data)
     })
 }
+
+// For a path that isn't in the tree, this proves its absence along with the hash at
+// NOT_FOUND_PATH.
+fn path_witness<'a>(hashes: &'a RbTree<String, Hash>, name: &str) -> HashTree<'a> {
+    let witness = hashes.witness(name.as_bytes());
+    if hashes.get(name.as_bytes()).is_none() {
+        merge(witness, hashes.witness(NOT_FOUND_PATH.as_bytes()))
+    } else {
+        witness
+    }
+}
+
+// Combines two witnesses of the same tree, which only differ in which subtrees they prune.
+fn merge<'a>(a: HashTree<'a>, b: HashTree<'a>) -> HashTree<'a> {
+    match (a, b) {
+        (HashTree::Pruned(_), b) => b,
+        (a, HashTree::Pruned(_)) => a,
+        (HashTree::Fork(a), HashTree::Fork(b)) => {
+            let ((a_left, a_right), (b_left, b_right)) = (*a, *b);
+            HashTree::Fork(Box::new((merge(a_left, b_left), merge(a_right, b_right))))
+        }
+        (HashTree::Labeled(label, a), HashTree::Labeled(_, b)) => {
+            HashTree::Labeled(label, Box::new(merge(*a, *b)))
+        }
+        (a, _) => a,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{stable, Nft};
+
+    fn hashes() -> RbTree<String, Hash> {
+        let mut hashes = RbTree::new();
+        for path in ["/", "/0", "/0/0", "/1", "/logo", "/collection.json"] {
+            hashes.insert(path.to_string(), Sha256::digest(path).into());
+        }
+        hashes.insert(NOT_FOUND_PATH.to_string(), Sha256::digest(NOT_FOUND).into());
+        hashes
+    }
+
+    // the leaf at `path` in a witness, if it isn't pruned away
+    fn lookup<'a>(tree: &'a HashTree<'a>, path: &str) -> Option<&'a [u8]> {
+        match tree {
+            HashTree::Fork(fork) => lookup(&fork.0, path).or_else(|| lookup(&fork.1, path)),
+            HashTree::Labeled(label, subtree) if label[..] == *path.as_bytes() => {
+                match &**subtree {
+                    HashTree::Leaf(data) => Some(&data[..]),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    // what a gateway checks the certified data against
+    fn certified(tree: HashTree) -> Hash {
+        ic_certified_map::labeled(b"http_assets", tree).reconstruct()
+    }
+
+    #[test]
+    fn witness_of_a_path_reveals_its_hash() {
+        let hashes = hashes();
+        let tree = path_witness(&hashes, "/0/0");
+        assert_eq!(lookup(&tree, "/0/0"), Some(&Sha256::digest("/0/0")[..]));
+        assert_eq!(lookup(&tree, NOT_FOUND_PATH), None);
+        assert_eq!(
+            certified(tree),
+            ic_certified_map::labeled_hash(b"http_assets", &hashes.root_hash())
+        );
+    }
+
+    #[test]
+    fn absence_proof_reveals_the_not_found_hash() {
+        let hashes = hashes();
+        for path in ["/nope", "/0/", "/0/7", "/2", ""] {
+            let tree = path_witness(&hashes, path);
+            assert_eq!(lookup(&tree, path), None);
+            assert_eq!(
+                lookup(&tree, NOT_FOUND_PATH),
+                Some(&Sha256::digest(NOT_FOUND)[..])
+            );
+            assert_eq!(
+                certified(tree),
+                ic_certified_map::labeled_hash(b"http_assets", &hashes.root_hash())
+            );
+        }
+    }
+
+    #[test]
+    fn merge_keeps_both_witnesses() {
+        let hashes = hashes();
+        let tree = merge(hashes.witness(b"/1"), hashes.witness(b"/logo"));
+        assert_eq!(lookup(&tree, "/1"), Some(&Sha256::digest("/1")[..]));
+        assert_eq!(lookup(&tree, "/logo"), Some(&Sha256::digest("/logo")[..]));
+        assert_eq!(tree.reconstruct(), hashes.root_hash());
+    }
+
+    // Token 0 has a small rendered part and a streamed preview, and token 1 is burned.
+    fn collection() {
+        stable::initialize();
+        let metadata = || {
+            vec![
+                MetadataPart {
+                    purpose: MetadataPurpose::Rendered,
+                    key_val_data: HashMap::from_iter([(
+                        "name".to_string(),
+                        MetadataVal::TextContent("Zero \"0\"".to_string()),
+                    )]),
+                    data: b"hello".to_vec(),
+                },
+                MetadataPart {
+                    purpose: MetadataPurpose::Preview,
+                    key_val_data: HashMap::new(),
+                    data: vec![7; 2 * CHUNK_SIZE + 10],
+                },
+            ]
+        };
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            state.name = "Test".to_string();
+            let owner = Principal::from_slice(&[1]);
+            state.push_nft(&Nft::new(owner, &metadata(), &[]));
+            state.push_nft(&Nft::new(owner, &metadata(), &[]));
+        });
+        add_hash(0);
+        add_hash(1);
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            let mut nft = state.nft(1).unwrap();
+            state.burn(1, &mut nft);
+        });
+        add_hash(1);
+    }
+
+    // the whole body, with the rest of a streamed one fetched through the callback
+    fn serve(url: &str) -> (u16, Vec<u8>) {
+        let response = STATE.with(|state| respond(&state.borrow(), url));
+        let mut body = response.body.into_owned();
+        let mut next = response
+            .streaming_strategy
+            .map(|StreamingStrategy::Callback { token, .. }| token);
+        while let Some(token) = next {
+            let path = format!("/{}/{}/{}", token.token_id, token.part, token.chunk);
+            let chunk = http_request_streaming_callback(token);
+            // certified on its own, as the callback's certificate claims
+            let hash = HASHES.with(|hashes| hashes.borrow().get(path.as_bytes()).copied());
+            let expected: Hash = Sha256::digest(&chunk.body).into();
+            assert_eq!(hash, Some(expected), "{}", path);
+            assert_eq!(chunk.certificate, certificate(&path));
+            body.extend_from_slice(&chunk.body);
+            next = chunk.token;
+        }
+        (response.status_code, body)
+    }
+
+    #[test]
+    fn served_bodies_match_their_certified_hashes() {
+        collection();
+        let paths = [
+            ("/", 200),
+            ("/logo", 200),
+            ("/collection.json", 200),
+            ("/0", 200),
+            ("/0/0", 200),
+            ("/0/1", 200),
+            ("/0/1/0", 200),
+            ("/0/1/2", 200),
+            ("/0/metadata.json", 200),
+            ("/1", 410),
+            ("/1/1", 410),
+            ("/1/1/0", 410),
+            ("/1/metadata.json", 410),
+            ("/index.html", 404),
+            ("/0/1/3", 404),
+            ("/0/2", 404),
+            ("/2", 404),
+            ("/nope", 404),
+        ];
+        for &(url, code) in &paths {
+            let (status_code, body) = serve(url);
+            assert_eq!(status_code, code, "{}", url);
+            HASHES.with(|hashes| {
+                let hashes = hashes.borrow();
+                let tree = path_witness(&hashes, url);
+                let hash = lookup(&tree, url).or_else(|| lookup(&tree, NOT_FOUND_PATH));
+                assert_eq!(hash, Some(&Sha256::digest(&body)[..]), "{}", url);
+                assert_eq!(
+                    certified(tree),
+                    ic_certified_map::labeled_hash(b"http_assets", &hashes.root_hash())
+                );
+            });
+        }
+        // the streamed part's chunks are certified one by one as well
+        assert_eq!(serve("/0/1/1").1, vec![7; CHUNK_SIZE]);
+        assert_eq!(serve("/0/1/2").1, vec![7; 10]);
+    }
+
+    #[test]
+    fn merge_with_itself_is_the_same_witness() {
+        let hashes = hashes();
+        let tree = merge(hashes.witness(b"/0"), hashes.witness(b"/0"));
+        assert_eq!(lookup(&tree, "/0"), Some(&Sha256::digest("/0")[..]));
+        assert_eq!(tree.reconstruct(), hashes.root_hash());
+    }
+}
@@ -0,0 +1,654 @@
+// ICRC-37, on top of the ICRC-7 interface. Its approvals are kept apart from the DIP721 ones: they
+// can expire and carry a memo, a token can have several of them, and they are only honored by
+// icrc37_transfer_from, as the DIP721 ones are only honored by the DIP721 methods. Any transfer of
+// a token clears both kinds of token approval.
+
+use candid::{CandidType, Principal};
+use ic_cdk::{api, export::candid};
+
+use crate::icrc7::{
+    check_batch_size, check_created_at, live_token, memo_too_long, take_value, untransferable,
+    Account, RecentTransfers, TimeError, Value, MAX_MEMO_SIZE, MAX_UPDATE_BATCH_SIZE, PAUSED,
+    SOULBOUND,
+};
+use crate::stable::Blob;
+use crate::{Approval, State, TransactionType, MGMT, STATE};
+
+const MAX_APPROVALS: usize = 32; // per token, and per owner for the collection
+const MAX_REVOKE_APPROVALS: usize = MAX_UPDATE_BATCH_SIZE;
+
+#[derive(CandidType, Deserialize, Clone)]
+struct ApprovalInfo {
+    spender: Account,
+    from_subaccount: Option<Vec<u8>>,
+    expires_at: Option<u64>,
+    memo: Option<Vec<u8>>,
+    created_at_time: u64,
+}
+
+impl ApprovalInfo {
+    fn new(spender: Principal, approval: &Approval) -> Self {
+        let memo = approval.memo.read();
+        ApprovalInfo {
+            spender: Account::of(spender),
+            from_subaccount: None,
+            expires_at: approval.expires_at,
+            memo: if memo.is_empty() { None } else { Some(memo) },
+            created_at_time: approval.created_at_time,
+        }
+    }
+
+    fn approval(&self) -> Approval {
+        Approval {
+            expires_at: self.expires_at,
+            created_at_time: self.created_at_time,
+            memo: Blob::new(self.memo.as_deref().unwrap_or_default()),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+struct ApproveTokenArg {
+    token_id: u128,
+    approval_info: ApprovalInfo,
+}
+
+#[derive(CandidType, Deserialize)]
+struct ApproveCollectionArg {
+    approval_info: ApprovalInfo,
+}
+
+#[derive(CandidType, Deserialize)]
+struct RevokeTokenApprovalArg {
+    spender: Option<Account>,
+    from_subaccount: Option<Vec<u8>>,
+    token_id: u128,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct RevokeCollectionApprovalArg {
+    spender: Option<Account>,
+    from_subaccount: Option<Vec<u8>>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct IsApprovedArg {
+    spender: Account,
+    from_subaccount: Option<Vec<u8>>,
+    token_id: u128,
+}
+
+#[derive(CandidType, Deserialize)]
+struct TokenApproval {
+    token_id: u128,
+    approval_info: ApprovalInfo,
+}
+
+#[derive(CandidType, Deserialize)]
+struct TransferFromArg {
+    spender_subaccount: Option<Vec<u8>>,
+    from: Account,
+    to: Account,
+    token_id: u128,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+enum ApproveTokenError {
+    InvalidSpender,
+    Unauthorized,
+    NonExistingTokenId,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: u128, message: String },
+    GenericBatchError { error_code: u128, message: String },
+}
+
+#[derive(CandidType, Deserialize)]
+enum ApproveCollectionError {
+    InvalidSpender,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: u128, message: String },
+    GenericBatchError { error_code: u128, message: String },
+}
+
+#[derive(CandidType, Deserialize)]
+enum RevokeTokenApprovalError {
+    ApprovalDoesNotExist,
+    Unauthorized,
+    NonExistingTokenId,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: u128, message: String },
+    GenericBatchError { error_code: u128, message: String },
+}
+
+#[derive(CandidType, Deserialize)]
+enum RevokeCollectionApprovalError {
+    ApprovalDoesNotExist,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: u128, message: String },
+    GenericBatchError { error_code: u128, message: String },
+}
+
+#[derive(CandidType, Deserialize)]
+enum TransferFromError {
+    InvalidRecipient,
+    Unauthorized,
+    NonExistingTokenId,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: u128 },
+    GenericError { error_code: u128, message: String },
+    GenericBatchError { error_code: u128, message: String },
+}
+
+type ApproveTokenResult = Result<u128, ApproveTokenError>;
+type ApproveCollectionResult = Result<u128, ApproveCollectionError>;
+type RevokeTokenApprovalResult = Result<u128, RevokeTokenApprovalError>;
+type RevokeCollectionApprovalResult = Result<u128, RevokeCollectionApprovalError>;
+type TransferFromResult = Result<u128, TransferFromError>;
+
+// every error type has the same time errors and generic errors
+macro_rules! error_helpers {
+    ($($error:ident),*) => {$(
+        impl From<TimeError> for $error {
+            fn from(e: TimeError) -> Self {
+                match e {
+                    TimeError::TooOld => Self::TooOld,
+                    TimeError::CreatedInFuture { ledger_time } => {
+                        Self::CreatedInFuture { ledger_time }
+                    }
+                }
+            }
+        }
+
+        impl $error {
+            fn generic(message: String) -> Self {
+                Self::GenericError {
+                    error_code: 0,
+                    message,
+                }
+            }
+
+            fn batch_too_large() -> Self {
+                Self::GenericBatchError {
+                    error_code: 0,
+                    message: format!(
+                        "At most {} items can be updated at once",
+                        MAX_UPDATE_BATCH_SIZE
+                    ),
+                }
+            }
+        }
+    )*};
+}
+
+error_helpers!(
+    ApproveTokenError,
+    ApproveCollectionError,
+    RevokeTokenApprovalError,
+    RevokeCollectionApprovalError,
+    TransferFromError
+);
+
+fn memo_error() -> String {
+    format!("The memo is longer than {} bytes", MAX_MEMO_SIZE)
+}
+
+fn too_many_approvals() -> String {
+    format!("At most {} approvals can be active at once", MAX_APPROVALS)
+}
+
+// a spender must be a principal other than the owner, through its default subaccount
+fn spender_of(spender: &Account, owner: Principal) -> Option<Principal> {
+    spender
+        .holder()
+        .filter(|&spender| spender != owner && spender != MGMT)
+}
+
+// sorted by spender, so that they can be paged through
+fn live_approvals(
+    mut approvals: Vec<(Principal, Approval)>,
+    now: u64,
+) -> Vec<(Principal, Approval)> {
+    approvals.retain(|(_, approval)| approval.is_live(now));
+    approvals.sort_by(|a, b| a.0.cmp(&b.0));
+    approvals
+}
+
+fn is_approved(
+    state: &State,
+    token_id: u64,
+    owner: Principal,
+    spender: Principal,
+    now: u64,
+) -> bool {
+    let live = |approval: Option<Approval>| approval.map_or(false, |a| a.is_live(now));
+    live(state.token_approval(token_id, spender)) || live(state.collection_approval(owner, spender))
+}
+
+// ------------------
+// metadata interface
+// ------------------
+
+#[query(name = "icrc37_metadata")]
+fn icrc37_metadata() -> Vec<(String, Value)> {
+    vec![
+        (
+            "icrc37:max_approvals_per_token_or_collection".to_string(),
+            Value::Nat(MAX_APPROVALS as u128),
+        ),
+        (
+            "icrc37:max_revoke_approvals".to_string(),
+            Value::Nat(MAX_REVOKE_APPROVALS as u128),
+        ),
+    ]
+}
+
+#[query(name = "icrc37_max_approvals_per_token_or_collection")]
+fn icrc37_max_approvals_per_token_or_collection() -> Option<u128> {
+    Some(MAX_APPROVALS as u128)
+}
+
+#[query(name = "icrc37_max_revoke_approvals")]
+fn icrc37_max_revoke_approvals() -> Option<u128> {
+    Some(MAX_REVOKE_APPROVALS as u128)
+}
+
+// ------------------
+// approval interface
+// ------------------
+
+// Like icrc7_transfer, none of the batch methods are atomic.
+#[update(name = "icrc37_approve_tokens")]
+fn icrc37_approve_tokens(args: Vec<ApproveTokenArg>) -> Vec<Option<ApproveTokenResult>> {
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        return vec![Some(Err(ApproveTokenError::batch_too_large()))];
+    }
+    let caller = api::caller();
+    let now = api::time();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.check_unpaused().is_err() {
+            return vec![Some(Err(ApproveTokenError::GenericBatchError {
+                error_code: 0,
+                message: PAUSED.to_string(),
+            }))];
+        }
+        args.iter()
+            .map(|arg| Some(approve_token(&mut state, caller, now, arg)))
+            .collect()
+    })
+}
+
+fn approve_token(
+    state: &mut State,
+    caller: Principal,
+    now: u64,
+    arg: &ApproveTokenArg,
+) -> ApproveTokenResult {
+    let info = &arg.approval_info;
+    if memo_too_long(&info.memo) {
+        return Err(ApproveTokenError::generic(memo_error()));
+    }
+    check_created_at(info.created_at_time, now)?;
+    let (token_id, owner) =
+        live_token(state, arg.token_id).ok_or(ApproveTokenError::NonExistingTokenId)?;
+    if owner != caller || !Account::is_default(&info.from_subaccount) {
+        return Err(ApproveTokenError::Unauthorized);
+    }
+    if state
+        .nft(token_id)
+        .map_or(false, |nft| state.is_soulbound(&nft))
+    {
+        return Err(ApproveTokenError::generic(SOULBOUND.to_string()));
+    }
+    let spender = spender_of(&info.spender, owner).ok_or(ApproveTokenError::InvalidSpender)?;
+    // expired approvals are dropped here rather than counting towards the limit
+    let mut others = 0;
+    for (other, approval) in state.token_approvals(token_id) {
+        if !approval.is_live(now) {
+            state.set_token_approval(token_id, other, None);
+        } else if other != spender {
+            others += 1;
+        }
+    }
+    if others >= MAX_APPROVALS {
+        return Err(ApproveTokenError::generic(too_many_approvals()));
+    }
+    state.set_token_approval(token_id, spender, Some(info.approval()));
+    Ok(state.record_tx(TransactionType::Approve {
+        token_id,
+        from: owner,
+        to: spender,
+    }))
+}
+
+#[update(name = "icrc37_approve_collection")]
+fn icrc37_approve_collection(
+    args: Vec<ApproveCollectionArg>,
+) -> Vec<Option<ApproveCollectionResult>> {
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        return vec![Some(Err(ApproveCollectionError::batch_too_large()))];
+    }
+    let caller = api::caller();
+    let now = api::time();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.check_unpaused().is_err() {
+            return vec![Some(Err(ApproveCollectionError::GenericBatchError {
+                error_code: 0,
+                message: PAUSED.to_string(),
+            }))];
+        }
+        args.iter()
+            .map(|arg| Some(approve_collection(&mut state, caller, now, arg)))
+            .collect()
+    })
+}
+
+fn approve_collection(
+    state: &mut State,
+    caller: Principal,
+    now: u64,
+    arg: &ApproveCollectionArg,
+) -> ApproveCollectionResult {
+    let info = &arg.approval_info;
+    if memo_too_long(&info.memo) {
+        return Err(ApproveCollectionError::generic(memo_error()));
+    }
+    check_created_at(info.created_at_time, now)?;
+    if !Account::is_default(&info.from_subaccount) {
+        return Err(ApproveCollectionError::generic(
+            "Only the default subaccount can hold tokens".to_string(),
+        ));
+    }
+    let spender =
+        spender_of(&info.spender, caller).ok_or(ApproveCollectionError::InvalidSpender)?;
+    let mut others = 0;
+    for (other, approval) in state.collection_approvals(caller) {
+        if !approval.is_live(now) {
+            state.set_collection_approval(caller, other, None);
+        } else if other != spender {
+            others += 1;
+        }
+    }
+    if others >= MAX_APPROVALS {
+        return Err(ApproveCollectionError::generic(too_many_approvals()));
+    }
+    state.set_collection_approval(caller, spender, Some(info.approval()));
+    Ok(state.record_tx(TransactionType::SetApprovalForAll {
+        from: caller,
+        to: spender,
+    }))
+}
+
+// Without a spender, every approval of the token is revoked.
+#[update(name = "icrc37_revoke_token_approvals")]
+fn icrc37_revoke_token_approvals(
+    args: Vec<RevokeTokenApprovalArg>,
+) -> Vec<Option<RevokeTokenApprovalResult>> {
+    if args.len() > MAX_REVOKE_APPROVALS {
+        return vec![Some(Err(RevokeTokenApprovalError::batch_too_large()))];
+    }
+    let caller = api::caller();
+    let now = api::time();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        args.iter()
+            .map(|arg| Some(revoke_token_approvals(&mut state, caller, now, arg)))
+            .collect()
+    })
+}
+
+fn revoke_token_approvals(
+    state: &mut State,
+    caller: Principal,
+    now: u64,
+    arg: &RevokeTokenApprovalArg,
+) -> RevokeTokenApprovalResult {
+    if memo_too_long(&arg.memo) {
+        return Err(RevokeTokenApprovalError::generic(memo_error()));
+    }
+    if let Some(created_at) = arg.created_at_time {
+        check_created_at(created_at, now)?;
+    }
+    let (token_id, owner) =
+        live_token(state, arg.token_id).ok_or(RevokeTokenApprovalError::NonExistingTokenId)?;
+    if owner != caller || !Account::is_default(&arg.from_subaccount) {
+        return Err(RevokeTokenApprovalError::Unauthorized);
+    }
+    let live = live_approvals(state.token_approvals(token_id), now);
+    let to = match arg.spender {
+        Some(ref spender) => {
+            let spender = spender
+                .holder()
+                .filter(|spender| live.iter().any(|(s, _)| s == spender))
+                .ok_or(RevokeTokenApprovalError::ApprovalDoesNotExist)?;
+            state.set_token_approval(token_id, spender, None);
+            spender
+        }
+        None if live.is_empty() => return Err(RevokeTokenApprovalError::ApprovalDoesNotExist),
+        None => {
+            state.clear_token_approvals(token_id);
+            MGMT
+        }
+    };
+    Ok(state.record_tx(TransactionType::Revoke {
+        token_id,
+        from: owner,
+        to,
+    }))
+}
+
+// Without a spender, every collection approval of the caller is revoked.
+#[update(name = "icrc37_revoke_collection_approvals")]
+fn icrc37_revoke_collection_approvals(
+    args: Vec<RevokeCollectionApprovalArg>,
+) -> Vec<Option<RevokeCollectionApprovalResult>> {
+    if args.len() > MAX_REVOKE_APPROVALS {
+        return vec![Some(Err(RevokeCollectionApprovalError::batch_too_large()))];
+    }
+    let caller = api::caller();
+    let now = api::time();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        args.iter()
+            .map(|arg| Some(revoke_collection_approvals(&mut state, caller, now, arg)))
+            .collect()
+    })
+}
+
+fn revoke_collection_approvals(
+    state: &mut State,
+    caller: Principal,
+    now: u64,
+    arg: &RevokeCollectionApprovalArg,
+) -> RevokeCollectionApprovalResult {
+    if memo_too_long(&arg.memo) {
+        return Err(RevokeCollectionApprovalError::generic(memo_error()));
+    }
+    if let Some(created_at) = arg.created_at_time {
+        check_created_at(created_at, now)?;
+    }
+    if !Account::is_default(&arg.from_subaccount) {
+        return Err(RevokeCollectionApprovalError::ApprovalDoesNotExist);
+    }
+    let live = live_approvals(state.collection_approvals(caller), now);
+    let to = match arg.spender {
+        Some(ref spender) => {
+            let spender = spender
+                .holder()
+                .filter(|spender| live.iter().any(|(s, _)| s == spender))
+                .ok_or(RevokeCollectionApprovalError::ApprovalDoesNotExist)?;
+            state.set_collection_approval(caller, spender, None);
+            spender
+        }
+        None if live.is_empty() => return Err(RevokeCollectionApprovalError::ApprovalDoesNotExist),
+        None => {
+            for (spender, _) in state.collection_approvals(caller) {
+                state.set_collection_approval(caller, spender, None);
+            }
+            MGMT
+        }
+    };
+    // logged the same way as a DIP721 setApprovalForAll, which doesn't tell the two apart either
+    Ok(state.record_tx(TransactionType::SetApprovalForAll { from: caller, to }))
+}
+
+#[query(name = "icrc37_is_approved")]
+fn icrc37_is_approved(args: Vec<IsApprovedArg>) -> Vec<bool> {
+    check_batch_size(args.len());
+    let now = api::time();
+    STATE.with(|state| {
+        let state = state.borrow();
+        args.iter()
+            .map(|arg| {
+                let token = live_token(&state, arg.token_id);
+                match (token, arg.spender.holder()) {
+                    (Some((token_id, owner)), Some(spender))
+                        if Account::is_default(&arg.from_subaccount) =>
+                    {
+                        is_approved(&state, token_id, owner, spender, now)
+                    }
+                    _ => false,
+                }
+            })
+            .collect()
+    })
+}
+
+#[query(name = "icrc37_get_token_approvals")]
+fn icrc37_get_token_approvals(
+    token_id: u128,
+    prev: Option<TokenApproval>,
+    take: Option<u128>,
+) -> Vec<TokenApproval> {
+    let now = api::time();
+    STATE.with(|state| {
+        let state = state.borrow();
+        let (id, _) = match live_token(&state, token_id) {
+            Some(token) => token,
+            None => return vec![],
+        };
+        let prev = prev.map(|prev| prev.approval_info.spender.owner);
+        live_approvals(state.token_approvals(id), now)
+            .into_iter()
+            .filter(|(spender, _)| prev.map_or(true, |prev| *spender > prev))
+            .take(take_value(take))
+            .map(|(spender, approval)| TokenApproval {
+                token_id,
+                approval_info: ApprovalInfo::new(spender, &approval),
+            })
+            .collect()
+    })
+}
+
+#[query(name = "icrc37_get_collection_approvals")]
+fn icrc37_get_collection_approvals(
+    owner: Account,
+    prev: Option<ApprovalInfo>,
+    take: Option<u128>,
+) -> Vec<ApprovalInfo> {
+    let owner = match owner.holder() {
+        Some(owner) => owner,
+        None => return vec![],
+    };
+    let now = api::time();
+    let prev = prev.map(|prev| prev.spender.owner);
+    STATE.with(|state| {
+        live_approvals(state.borrow().collection_approvals(owner), now)
+            .into_iter()
+            .filter(|(spender, _)| prev.map_or(true, |prev| *spender > prev))
+            .take(take_value(take))
+            .map(|(spender, approval)| ApprovalInfo::new(spender, &approval))
+            .collect()
+    })
+}
+
+// ------------------
+// transfer interface
+// ------------------
+
+// Only approved spenders can use this; owners transfer with icrc7_transfer.
+#[update(name = "icrc37_transfer_from")]
+fn icrc37_transfer_from(args: Vec<TransferFromArg>) -> Vec<Option<TransferFromResult>> {
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        return vec![Some(Err(TransferFromError::batch_too_large()))];
+    }
+    let caller = api::caller();
+    let now = api::time();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = &mut *state;
+        if state.check_unpaused().is_err() {
+            return vec![Some(Err(TransferFromError::GenericBatchError {
+                error_code: 0,
+                message: PAUSED.to_string(),
+            }))];
+        }
+        state
+            .icrc7_recent
+            .get_or_insert_with(Default::default)
+            .prune(now);
+        args.iter()
+            .map(|arg| Some(transfer_from(state, caller, now, arg)))
+            .collect()
+    })
+}
+
+fn transfer_from(
+    state: &mut State,
+    caller: Principal,
+    now: u64,
+    arg: &TransferFromArg,
+) -> TransferFromResult {
+    if memo_too_long(&arg.memo) {
+        return Err(TransferFromError::generic(memo_error()));
+    }
+    let key = RecentTransfers::key(&caller, arg);
+    if let Some(created_at) = arg.created_at_time {
+        check_created_at(created_at, now)?;
+        let recent = state.icrc7_recent.get_or_insert_with(Default::default);
+        if let Some(txid) = recent.get(&key) {
+            return Err(TransferFromError::Duplicate { duplicate_of: txid });
+        }
+    }
+    let (token_id, owner) =
+        live_token(state, arg.token_id).ok_or(TransferFromError::NonExistingTokenId)?;
+    if arg.from.holder() != Some(owner)
+        || !Account::is_default(&arg.spender_subaccount)
+        || !is_approved(state, token_id, owner, caller, now)
+    {
+        return Err(TransferFromError::Unauthorized);
+    }
+    let to = match arg.to.holder() {
+        Some(to) if to != MGMT => to,
+        _ => return Err(TransferFromError::InvalidRecipient),
+    };
+    let mut nft = state
+        .nft(token_id)
+        .map_err(|_| TransferFromError::NonExistingTokenId)?;
+    state
+        .check_transferable(&nft)
+        .map_err(|e| TransferFromError::generic(untransferable(e)))?;
+    state.transfer(token_id, &mut nft, to);
+    let txid = state.record_tx(TransactionType::TransferFrom {
+        token_id,
+        from: owner,
+        to,
+    });
+    if let Some(created_at) = arg.created_at_time {
+        let recent = state.icrc7_recent.get_or_insert_with(Default::default);
+        recent.insert(key, created_at, txid);
+    }
+    Ok(txid)
+}
@@ -0,0 +1,489 @@
+// ICRC-7, served from the same State as the DIP721 methods. Tokens are owned by principals, so an
+// ICRC account owns a token only through its default subaccount, and no other subaccount can
+// receive one.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use candid::{CandidType, Encode, Principal};
+use ic_cdk::{api, export::candid};
+use ic_certified_map::Hash;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    Error, MetadataPurpose, MetadataVal, State, TransactionType, MAX_TOKENS_PER_QUERY, MGMT, STATE,
+};
+
+// shared with ICRC-37, which uses the same limits
+pub const MAX_QUERY_BATCH_SIZE: usize = 100;
+pub const MAX_UPDATE_BATCH_SIZE: usize = 100;
+pub const DEFAULT_TAKE_VALUE: u64 = 100;
+pub const MAX_MEMO_SIZE: usize = 32;
+pub const PAUSED: &str = "The canister is paused";
+pub const FROZEN: &str = "The token is frozen";
+pub const SOULBOUND: &str = "The token is soulbound";
+const TX_WINDOW: u64 = 24 * 60 * 60 * 1_000_000_000; // nanoseconds
+const PERMITTED_DRIFT: u64 = 2 * 60 * 1_000_000_000;
+
+// the message for an error from State::check_transferable
+pub(crate) fn untransferable(error: Error) -> String {
+    match error {
+        Error::Soulbound => SOULBOUND,
+        Error::Paused => PAUSED,
+        _ => FROZEN,
+    }
+    .to_string()
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+impl Account {
+    pub fn is_default(subaccount: &Option<Vec<u8>>) -> bool {
+        subaccount
+            .as_ref()
+            .map_or(true, |s| s.iter().all(|&b| b == 0))
+    }
+
+    // the principal that holds this account's tokens, if it can hold any
+    pub fn holder(&self) -> Option<Principal> {
+        if Self::is_default(&self.subaccount) {
+            Some(self.owner)
+        } else {
+            None
+        }
+    }
+
+    pub fn of(owner: Principal) -> Self {
+        Account {
+            owner,
+            subaccount: None,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(u128),
+    Int(i128),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+impl From<&MetadataVal> for Value {
+    fn from(val: &MetadataVal) -> Self {
+        match *val {
+            MetadataVal::TextContent(ref s) => Value::Text(s.clone()),
+            MetadataVal::BlobContent(ref b) => Value::Blob(b.clone()),
+            MetadataVal::NatContent(n) => Value::Nat(n),
+            MetadataVal::Nat8Content(n) => Value::Nat(n.into()),
+            MetadataVal::Nat16Content(n) => Value::Nat(n.into()),
+            MetadataVal::Nat32Content(n) => Value::Nat(n.into()),
+            MetadataVal::Nat64Content(n) => Value::Nat(n.into()),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+struct TransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    token_id: u128,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+enum TransferError {
+    NonExistingTokenId,
+    InvalidRecipient,
+    Unauthorized,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: u128 },
+    GenericError { error_code: u128, message: String },
+    GenericBatchError { error_code: u128, message: String },
+}
+
+type TransferResult = Result<u128, TransferError>;
+
+#[derive(CandidType)]
+struct Standard {
+    name: &'static str,
+    url: &'static str,
+}
+
+pub enum TimeError {
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+}
+
+impl From<TimeError> for TransferError {
+    fn from(e: TimeError) -> Self {
+        match e {
+            TimeError::TooOld => Self::TooOld,
+            TimeError::CreatedInFuture { ledger_time } => Self::CreatedInFuture { ledger_time },
+        }
+    }
+}
+
+pub fn check_created_at(created_at: u64, now: u64) -> Result<(), TimeError> {
+    if created_at < now.saturating_sub(TX_WINDOW + PERMITTED_DRIFT) {
+        Err(TimeError::TooOld)
+    } else if created_at > now.saturating_add(PERMITTED_DRIFT) {
+        Err(TimeError::CreatedInFuture { ledger_time: now })
+    } else {
+        Ok(())
+    }
+}
+
+pub fn memo_too_long(memo: &Option<Vec<u8>>) -> bool {
+    memo.as_ref()
+        .map_or(false, |memo| memo.len() > MAX_MEMO_SIZE)
+}
+
+// Transfers with a created_at_time are remembered for the length of the deduplication window,
+// keyed by the hash of the caller and the arguments.
+#[derive(CandidType, Deserialize, Default)]
+pub struct RecentTransfers(HashMap<Hash, (u64, u128)>); // hash to (created_at_time, txid)
+
+impl RecentTransfers {
+    pub fn key<T: CandidType>(caller: &Principal, arg: &T) -> Hash {
+        Sha256::digest(&Encode!(caller, arg).unwrap()).into()
+    }
+
+    pub fn prune(&mut self, now: u64) {
+        let oldest = now.saturating_sub(TX_WINDOW + PERMITTED_DRIFT);
+        self.0
+            .retain(|_, &mut (created_at, _)| created_at >= oldest);
+    }
+
+    pub fn get(&self, key: &Hash) -> Option<u128> {
+        self.0.get(key).map(|&(_, txid)| txid)
+    }
+
+    pub fn insert(&mut self, key: Hash, created_at: u64, txid: u128) {
+        self.0.insert(key, (created_at, txid));
+    }
+}
+
+pub fn token_id(token_id: u128) -> Option<u64> {
+    u64::try_from(token_id).ok()
+}
+
+pub(crate) fn live_token(state: &State, token_id: u128) -> Option<(u64, Principal)> {
+    let token_id = self::token_id(token_id)?;
+    Some((token_id, state.live_owner(token_id)?))
+}
+
+pub fn check_batch_size(len: usize) {
+    if len > MAX_QUERY_BATCH_SIZE {
+        api::trap(&format!(
+            "At most {} items can be queried at once",
+            MAX_QUERY_BATCH_SIZE
+        ));
+    }
+}
+
+pub fn take_value(take: Option<u128>) -> usize {
+    take.map_or(DEFAULT_TAKE_VALUE, |take| {
+        take.min(MAX_TOKENS_PER_QUERY as u128) as u64
+    }) as usize
+}
+
+// --------------------
+// collection interface
+// --------------------
+
+#[query(name = "icrc7_collection_metadata")]
+fn icrc7_collection_metadata() -> Vec<(String, Value)> {
+    let mut metadata = vec![
+        ("icrc7:symbol".to_string(), Value::Text(icrc7_symbol())),
+        ("icrc7:name".to_string(), Value::Text(icrc7_name())),
+        (
+            "icrc7:total_supply".to_string(),
+            Value::Nat(icrc7_total_supply()),
+        ),
+    ];
+    if let Some(logo) = icrc7_logo() {
+        metadata.push(("icrc7:logo".to_string(), Value::Text(logo)));
+    }
+    let limits = [
+        ("icrc7:max_query_batch_size", MAX_QUERY_BATCH_SIZE as u128),
+        ("icrc7:max_update_batch_size", MAX_UPDATE_BATCH_SIZE as u128),
+        ("icrc7:default_take_value", DEFAULT_TAKE_VALUE as u128),
+        ("icrc7:max_take_value", MAX_TOKENS_PER_QUERY as u128),
+        ("icrc7:max_memo_size", MAX_MEMO_SIZE as u128),
+        ("icrc7:tx_window", TX_WINDOW as u128),
+        ("icrc7:permitted_drift", PERMITTED_DRIFT as u128),
+    ];
+    for &(key, value) in &limits {
+        metadata.push((key.to_string(), Value::Nat(value)));
+    }
+    metadata
+}
+
+#[query(name = "icrc7_symbol")]
+fn icrc7_symbol() -> String {
+    crate::symbol()
+}
+
+#[query(name = "icrc7_name")]
+fn icrc7_name() -> String {
+    crate::name()
+}
+
+#[query(name = "icrc7_description")]
+fn icrc7_description() -> Option<String> {
+    None
+}
+
+#[query(name = "icrc7_logo")]
+fn icrc7_logo() -> Option<String> {
+    STATE.with(|state| Some(state.borrow().logo_data_uri()))
+}
+
+// burned tokens don't count
+#[query(name = "icrc7_total_supply")]
+fn icrc7_total_supply() -> u128 {
+    STATE.with(|state| state.borrow().supply() as u128)
+}
+
+#[query(name = "icrc7_supply_cap")]
+fn icrc7_supply_cap() -> Option<u128> {
+    None
+}
+
+#[query(name = "icrc7_max_query_batch_size")]
+fn icrc7_max_query_batch_size() -> Option<u128> {
+    Some(MAX_QUERY_BATCH_SIZE as u128)
+}
+
+#[query(name = "icrc7_max_update_batch_size")]
+fn icrc7_max_update_batch_size() -> Option<u128> {
+    Some(MAX_UPDATE_BATCH_SIZE as u128)
+}
+
+#[query(name = "icrc7_default_take_value")]
+fn icrc7_default_take_value() -> Option<u128> {
+    Some(DEFAULT_TAKE_VALUE as u128)
+}
+
+#[query(name = "icrc7_max_take_value")]
+fn icrc7_max_take_value() -> Option<u128> {
+    Some(MAX_TOKENS_PER_QUERY as u128)
+}
+
+#[query(name = "icrc7_max_memo_size")]
+fn icrc7_max_memo_size() -> Option<u128> {
+    Some(MAX_MEMO_SIZE as u128)
+}
+
+#[query(name = "icrc7_atomic_batch_transfers")]
+fn icrc7_atomic_batch_transfers() -> Option<bool> {
+    Some(false)
+}
+
+#[query(name = "icrc7_tx_window")]
+fn icrc7_tx_window() -> Option<u128> {
+    Some(TX_WINDOW as u128)
+}
+
+#[query(name = "icrc7_permitted_drift")]
+fn icrc7_permitted_drift() -> Option<u128> {
+    Some(PERMITTED_DRIFT as u128)
+}
+
+#[query(name = "icrc10_supported_standards")]
+fn icrc10_supported_standards() -> Vec<Standard> {
+    vec![
+        Standard {
+            name: "ICRC-7",
+            url: "https://github.com/dfinity/ICRC/blob/main/ICRCs/ICRC-7",
+        },
+        Standard {
+            name: "ICRC-10",
+            url: "https://github.com/dfinity/ICRC/blob/main/ICRCs/ICRC-10",
+        },
+        Standard {
+            name: "ICRC-37",
+            url: "https://github.com/dfinity/ICRC/blob/main/ICRCs/ICRC-37",
+        },
+    ]
+}
+
+// ---------------
+// token interface
+// ---------------
+
+// A token's metadata is the key-value data of the part served at /<nft>, as for DIP721 v2.
+#[query(name = "icrc7_token_metadata")]
+fn icrc7_token_metadata(token_ids: Vec<u128>) -> Vec<Option<Vec<(String, Value)>>> {
+    check_batch_size(token_ids.len());
+    STATE.with(|state| {
+        let state = state.borrow();
+        token_ids
+            .into_iter()
+            .map(|token_id| {
+                let (token_id, _) = live_token(&state, token_id)?;
+                let desc = state.metadata(token_id);
+                let part = desc
+                    .iter()
+                    .find(|part| part.purpose == MetadataPurpose::Rendered)
+                    .or_else(|| desc.get(0));
+                let mut metadata: Vec<_> = part
+                    .into_iter()
+                    .flat_map(|part| &part.key_val_data)
+                    .map(|(key, val)| (key.clone(), val.into()))
+                    .collect();
+                metadata.sort_by(|a, b| a.0.cmp(&b.0));
+                Some(metadata)
+            })
+            .collect()
+    })
+}
+
+#[query(name = "icrc7_owner_of")]
+fn icrc7_owner_of(token_ids: Vec<u128>) -> Vec<Option<Account>> {
+    check_batch_size(token_ids.len());
+    STATE.with(|state| {
+        let state = state.borrow();
+        token_ids
+            .into_iter()
+            .map(|token_id| live_token(&state, token_id).map(|(_, owner)| Account::of(owner)))
+            .collect()
+    })
+}
+
+#[query(name = "icrc7_balance_of")]
+fn icrc7_balance_of(accounts: Vec<Account>) -> Vec<u128> {
+    check_batch_size(accounts.len());
+    STATE.with(|state| {
+        let state = state.borrow();
+        accounts
+            .iter()
+            .map(|account| match account.holder() {
+                Some(owner) if owner != MGMT => state.balance_of(&owner) as u128,
+                _ => 0,
+            })
+            .collect()
+    })
+}
+
+#[query(name = "icrc7_tokens")]
+fn icrc7_tokens(prev: Option<u128>, take: Option<u128>) -> Vec<u128> {
+    let start = prev.map_or(0, |prev| prev.saturating_add(1));
+    STATE.with(|state| {
+        let state = state.borrow();
+        (start..state.nft_count() as u128)
+            .filter(|&token_id| live_token(&state, token_id).is_some())
+            .take(take_value(take))
+            .collect()
+    })
+}
+
+#[query(name = "icrc7_tokens_of")]
+fn icrc7_tokens_of(account: Account, prev: Option<u128>, take: Option<u128>) -> Vec<u128> {
+    let owner = match account.holder() {
+        Some(owner) if owner != MGMT => owner,
+        _ => return vec![],
+    };
+    STATE.with(|state| {
+        state
+            .borrow()
+            .tokens_of(&owner)
+            .into_iter()
+            .map(u128::from)
+            .filter(|&token_id| prev.map_or(true, |prev| token_id > prev))
+            .take(take_value(take))
+            .collect()
+    })
+}
+
+// ------------------
+// transfer interface
+// ------------------
+
+// Not atomic: each transfer succeeds or fails on its own, in order.
+#[update(name = "icrc7_transfer")]
+fn icrc7_transfer(args: Vec<TransferArg>) -> Vec<Option<TransferResult>> {
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        return vec![Some(Err(TransferError::GenericBatchError {
+            error_code: 0,
+            message: format!(
+                "At most {} transfers can be made at once",
+                MAX_UPDATE_BATCH_SIZE
+            ),
+        }))];
+    }
+    let caller = api::caller();
+    let now = api::time();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = &mut *state;
+        if state.check_unpaused().is_err() {
+            return vec![Some(Err(TransferError::GenericBatchError {
+                error_code: 0,
+                message: PAUSED.to_string(),
+            }))];
+        }
+        state
+            .icrc7_recent
+            .get_or_insert_with(Default::default)
+            .prune(now);
+        args.iter()
+            .map(|arg| Some(transfer(state, caller, now, arg)))
+            .collect()
+    })
+}
+
+fn transfer(state: &mut State, caller: Principal, now: u64, arg: &TransferArg) -> TransferResult {
+    if memo_too_long(&arg.memo) {
+        return Err(TransferError::GenericError {
+            error_code: 0,
+            message: format!("The memo is longer than {} bytes", MAX_MEMO_SIZE),
+        });
+    }
+    let key = RecentTransfers::key(&caller, arg);
+    if let Some(created_at) = arg.created_at_time {
+        check_created_at(created_at, now)?;
+        let recent = state.icrc7_recent.get_or_insert_with(Default::default);
+        if let Some(txid) = recent.get(&key) {
+            return Err(TransferError::Duplicate { duplicate_of: txid });
+        }
+    }
+    let (token_id, owner) =
+        live_token(state, arg.token_id).ok_or(TransferError::NonExistingTokenId)?;
+    if owner != caller || !Account::is_default(&arg.from_subaccount) {
+        return Err(TransferError::Unauthorized);
+    }
+    let to = match arg.to.holder() {
+        Some(to) if to != MGMT => to,
+        _ => return Err(TransferError::InvalidRecipient),
+    };
+    let mut nft = state
+        .nft(token_id)
+        .map_err(|_| TransferError::NonExistingTokenId)?;
+    state
+        .check_transferable(&nft)
+        .map_err(|e| TransferError::GenericError {
+            error_code: 0,
+            message: untransferable(e),
+        })?;
+    state.transfer(token_id, &mut nft, to);
+    let txid = state.record_tx(TransactionType::Transfer {
+        token_id,
+        from: owner,
+        to,
+    });
+    if let Some(created_at) = arg.created_at_time {
+        let recent = state.icrc7_recent.get_or_insert_with(Default::default);
+        recent.insert(key, created_at, txid);
+    }
+    Ok(txid)
+}
@@ -10,7 +10,6 @@ use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use std::iter::FromIterator;
-use std::mem;
 use std::num::TryFromIntError;
 use std::result::Result as StdResult;
 
@@ -18,7 +17,6 @@ use candid::{CandidType, Encode, Principal};
 use ic_cdk::{
     api::{self, call},
     export::candid,
-    storage,
 };
 use ic_certified_map::Hash;
 use include_base64::include_base64;
@@ -27,7 +25,14 @@ use sha2::{Digest, Sha256};
 use uriparse::URI;
 
 mod http;
+mod icrc37;
+mod icrc7;
+mod proposals;
+mod stable;
+mod uploads;
+mod v2;
 
+use stable::{Blob, Fixed, StableMap, StableSetMap, StableVec};
 
 const MGMT: Principal = Principal::from_slice(&[]);
 
@@ -35,26 +40,134 @@ thread_local! {
     static STATE: RefCell<State> = RefCell::default();
 }
 
-#[derive(CandidType, Deserialize)]
-struct StableState {
-    state: State,
-    hashes: Vec<(String, Hash)>,
-}
+// Everything that grows with the collection lives in stable memory and is updated in place,
+// so an upgrade only has to save the heap `State`. The numbers are header slots in stable memory;
+// never reuse one for something else.
+const NFTS: StableVec<Nft> = StableVec::new(0);
+const OWNERS: StableSetMap<Principal, u64> = StableSetMap::new(1); // slots 1-3, owner to token ids
+const OPERATORS: StableSetMap<Principal, Principal> = StableSetMap::new(4); // slots 4-6, owner to operators
+const TRANSACTIONS: StableVec<Blob> = StableVec::new(7);
+const HTTP_HASHES: StableMap<http::HashPath, Hash> = StableMap::new(8);
+const APPROVALS: StableSetMap<Principal, u64> = StableSetMap::new(9); // slots 9-11, approved principal to token ids
+const HISTORY: StableVec<TokenHistory> = StableVec::new(12); // by token id, may be shorter than NFTS
+const TOKEN_SPENDERS: StableSetMap<u64, Principal> = StableSetMap::new(13); // slots 13-15, ICRC-37 token approvals
+const TOKEN_APPROVALS: StableMap<(u64, Principal), Approval> = StableMap::new(16);
+const COLLECTION_SPENDERS: StableSetMap<Principal, Principal> = StableSetMap::new(17); // slots 17-19, ICRC-37 collection approvals
+const COLLECTION_APPROVALS: StableMap<(Principal, Principal), Approval> = StableMap::new(20);
+const MINT_COUNTS: StableMap<Principal, u64> = StableMap::new(21); // caller to tokens minted
 
 #[pre_upgrade]
 fn pre_upgrade() {
-    let state = STATE.with(|state| mem::take(&mut *state.borrow_mut()));
-    let hashes = http::HASHES.with(|hashes| mem::take(&mut *hashes.borrow_mut()));
-    let hashes = hashes.iter().map(|(k, v)| (k.clone(), *v)).collect();
-    let stable_state = StableState { state, hashes };
-    storage::stable_save((stable_state,)).unwrap();
+    STATE.with(|state| stable::save_state(&*state.borrow()));
 }
 #[post_upgrade]
 fn post_upgrade() {
-    let (StableState { state, hashes },) = storage::stable_restore().unwrap();
+    if stable::is_initialized() {
+        let state = stable::restore_state();
+        STATE.with(|state0| *state0.borrow_mut() = state);
+    } else {
+        migrate_legacy_state();
+    }
+    let (burned, hashes_version) = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.upgraded_at = Some(api::time());
+        (state.mark_legacy_burns(), state.hashes_version)
+    });
+    // The certification tree only lives on the heap, so it is rebuilt on every upgrade, but from
+    // the stored hashes alone; none of the content they were computed from is read.
+    http::restore_hashes();
+    for token_id in burned {
+        http::add_hash(token_id);
+    }
+    if hashes_version != Some(http::HASHES_VERSION) {
+        http::rehash_uncertified(STATE.with(|state| state.borrow().nft_count()));
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            // versions before /logo, /collection.json and /index.html have no hashes for them
+            http::update_collection(&state);
+            state.hashes_version = Some(http::HASHES_VERSION);
+        });
+    }
+}
+
+// Before the move to stable memory, pre_upgrade saved everything with storage::stable_save.
+// `txid` is from the versions before the transaction log, `txs` from the ones with it.
+#[derive(CandidType, Deserialize)]
+struct LegacyStableState {
+    state: LegacyState,
+    hashes: Vec<(String, Hash)>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LegacyState {
+    nfts: Vec<LegacyNft>,
+    custodians: HashSet<Principal>,
+    operators: HashMap<Principal, HashSet<Principal>>,
+    logo: Option<LogoResult>,
+    name: String,
+    symbol: String,
+    txid: Option<u128>,
+    txs: Option<Vec<TxResult>>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LegacyNft {
+    owner: Principal,
+    approved: Option<Principal>,
+    id: u64,
+    metadata: MetadataDesc,
+    content: Vec<u8>,
+}
+
+fn migrate_legacy_state() {
+    let LegacyStableState {
+        state: legacy,
+        hashes,
+    } = stable::restore_legacy().unwrap();
+    // everything is on the heap now, so the old image can be overwritten
+    stable::initialize();
+    let mut state = State {
+        custodians: legacy.custodians,
+        logo: legacy.logo,
+        name: legacy.name,
+        symbol: legacy.symbol,
+        tx_base: 0,
+        created_at: None,
+        upgraded_at: None,
+        icrc7_recent: None,
+        burned: None,
+        purge_burned: None,
+        mint_policy: None,
+        roles: None,
+        proposals: None,
+        paused: None,
+        soulbound: None,
+        uploads: None,
+        hashes_version: None,
+    };
+    for nft in legacy.nfts {
+        let mut new_nft = Nft::new(nft.owner, &nft.metadata, &nft.content);
+        new_nft.approved = nft.approved;
+        state.push_nft(&new_nft);
+    }
+    for (owner, operators) in legacy.operators {
+        for operator in operators {
+            state.set_operator(&owner, &operator, true);
+        }
+    }
+    match legacy.txs {
+        Some(txs) => {
+            for tx in &txs {
+                TRANSACTIONS.push(&Blob::encode(tx));
+            }
+        }
+        // those transactions were never recorded, but their txids stay taken
+        None => state.tx_base = legacy.txid.unwrap_or(0),
+    }
+    for (path, hash) in hashes {
+        HTTP_HASHES.insert(&http::HashPath(path), &hash);
+    }
     STATE.with(|state0| *state0.borrow_mut() = state);
-    let hashes = hashes.into_iter().collect();
-    http::HASHES.with(|hashes0| *hashes0.borrow_mut() = hashes);
 }
 
 #[derive(CandidType, Deserialize)]
@@ -63,10 +176,15 @@ struct InitArgs {
     logo: Option<LogoResult>,
     name: String,
     symbol: String,
+    purge_burned: Option<bool>,
+    mint_policy: Option<MintPolicy>,
+    proposal_threshold: Option<u64>,
+    soulbound: Option<bool>,
 }
 
 #[init]
 fn init(args: InitArgs) {
+    stable::initialize();
     STATE.with(|state| {
         let mut state = state.borrow_mut();
         state.custodians = args
@@ -75,14 +193,33 @@ fn init(args: InitArgs) {
         state.name = args.name;
         state.symbol = args.symbol;
         state.logo = args.logo;
+        state.created_at = Some(api::time());
+        state.burned = Some(0);
+        state.purge_burned = args.purge_burned;
+        state.soulbound = args.soulbound;
+        state.mint_policy = args.mint_policy;
+        state.proposals = Some(proposals::Proposals::new(
+            args.proposal_threshold.unwrap_or(1),
+        ));
+        state.hashes_version = Some(http::HASHES_VERSION);
+        http::update_collection(&state);
     });
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Clone)]
 enum Error {
     Unauthorized,
     InvalidTokenId,
     ZeroAddress,
+    InvalidTxId,
+    LastAdmin,
+    InvalidProposalId,
+    Paused,
+    TokenFrozen,
+    Soulbound,
+    InvalidUploadId,
+    HashMismatch,
+    UploadQuotaExceeded,
     Other,
 }
 
@@ -100,55 +237,41 @@ type Result<T = u128, E = Error> = StdResult<T, E>;
 
 #[query(name = "balanceOfDip721")]
 fn balance_of(user: Principal) -> u64 {
-    STATE.with(|state| {
-        state
-            .borrow()
-            .nfts
-            .iter()
-            .filter(|n| n.owner == user)
-            .count() as u64
-    })
+    STATE.with(|state| state.borrow().balance_of(&user))
 }
 
 #[query(name = "ownerOfDip721")]
 fn owner_of(token_id: u64) -> Result<Principal> {
-    STATE.with(|state| {
-        let owner = state
-            .borrow()
-            .nfts
-            .get(usize::try_from(token_id)?)
-            .ok_or(Error::InvalidTokenId)?
-            .owner;
-        Ok(owner)
-    })
+    STATE.with(|state| Ok(state.borrow().live_nft(token_id)?.owner))
 }
 
 #[update(name = "transferFromDip721")]
 fn transfer_from(from: Principal, to: Principal, token_id: u64) -> Result {
+    // a token sent to the burn address would be lost without being counted as burned
+    if to == MGMT {
+        return Err(Error::ZeroAddress);
+    }
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        let state = &mut *state;
-        let nft = state
-            .nfts
-            .get_mut(usize::try_from(token_id)?)
-            .ok_or(Error::InvalidTokenId)?;
+        let mut nft = state.live_nft(token_id)?;
         let caller = api::caller();
         if nft.owner != caller
             && nft.approved != Some(caller)
-            && !state
-                .operators
-                .get(&from)
-                .map(|s| s.contains(&caller))
-                .unwrap_or(false)
-            && !state.custodians.contains(&caller)
+            && !state.is_operator(&nft.owner, &caller)
+            && !state.acts_alone(&caller)
         {
             Err(Error::Unauthorized)
         } else if nft.owner != from {
             Err(Error::Other)
         } else {
-            nft.approved = None;
-            nft.owner = to;
-            Ok(state.next_txid())
+            state.check_transferable(&nft)?;
+            state.transfer(token_id, &mut nft, to);
+            let transaction_type = if caller == from {
+                TransactionType::Transfer { token_id, from, to }
+            } else {
+                TransactionType::TransferFrom { token_id, from, to }
+            };
+            Ok(state.record_tx(transaction_type))
         }
     })
 }
@@ -169,7 +292,10 @@ fn supported_interfaces() -> &'static [InterfaceId] {
         InterfaceId::Approval, // Psychedelic/DIP721#5
         InterfaceId::Burn,
         InterfaceId::Mint,
-    ]    
+        InterfaceId::TransactionHistory,
+        InterfaceId::Pause,
+        InterfaceId::Freeze,
+    ]
 }
 
 #[derive(CandidType, Deserialize, Clone)]
@@ -200,56 +326,110 @@ const DEFAULT_LOGO: LogoResult = LogoResult {
     logo_type: Cow::Borrowed("image/png"),
 };
 
+// burned tokens don't count
 #[query(name = "totalSupplyDip721")]
 fn total_supply() -> u64 {
-    STATE.with(|state| state.borrow().nfts.len() as u64)
+    STATE.with(|state| state.borrow().supply())
 }
 
-#[export_name = "canister_query getMetadataDip721"]
-fn get_metadata(/* token_id: u64 */) /* -> Result<&'static MetadataDesc> */
-{
-    ic_cdk::setup();
-    let token_id = call::arg_data::<(u64,)>().0;
-    let res: Result<()> = STATE.with(|state| {
-        let state = state.borrow();
-        let metadata = &state
-            .nfts
-            .get(usize::try_from(token_id)?)
-            .ok_or(Error::InvalidTokenId)?
-            .metadata;
-        call::reply((Ok::<_, Error>(metadata),));
-        Ok(())
-    });
-    if let Err(e) = res {
-        call::reply((Err::<MetadataDesc, _>(e),));
-    }
+// Metadata is decoded out of stable memory for every call, so unlike the heap-backed
+// version there is nothing to gain from replying with borrowed data.
+#[query(name = "getMetadataDip721")]
+fn get_metadata(token_id: u64) -> Result<MetadataDesc> {
+    STATE.with(|state| Ok(state.borrow().nft(token_id)?.metadata()))
 }
 
 #[derive(CandidType)]
-struct ExtendedMetadataResult<'a> {
-    metadata_desc: MetadataDescRef<'a>,
+struct ExtendedMetadataResult {
+    metadata_desc: MetadataDesc,
     token_id: u64,
 }
 
-#[export_name = "canister_query getMetadataForUserDip721"]
-fn get_metadata_for_user(/* user: Principal */) /* -> Vec<ExtendedMetadataResult> */
-{
-    ic_cdk::setup();
-    let user = call::arg_data::<(Principal,)>().0;
+#[query(name = "getMetadataForUserDip721")]
+fn get_metadata_for_user(user: Principal) -> Vec<ExtendedMetadataResult> {
     STATE.with(|state| {
         let state = state.borrow();
-        let metadata: Vec<_> = state
-            .nfts
-            .iter()
-            .filter(|n| n.owner == user)
-            .map(|n| ExtendedMetadataResult {
+        state
+            .tokens_of(&user)
+            .into_iter()
+            .map(|token_id| ExtendedMetadataResult {
+                metadata_desc: state.metadata(token_id),
+                token_id,
+            })
+            .collect()
+    })
+}
 
-                metadata_desc: &n.metadata,
-                token_id: n.id,
+#[query(name = "getTokenIdsForUserDip721")]
+fn get_token_ids_for_user(user: Principal) -> Vec<u64> {
+    STATE.with(|state| state.borrow().tokens_of(&user))
+}
+
+const MAX_PAGE_SIZE: u64 = 100;
+const MAX_TOKENS_PER_QUERY: u64 = 1000;
+
+#[derive(CandidType)]
+struct ExtendedMetadataPage {
+    items: Vec<ExtendedMetadataResult>,
+    // pass as `cursor` to fetch the next page; absent on the last page
+    next: Option<u64>,
+}
+
+#[query(name = "getMetadataForUserPageDip721")]
+fn get_metadata_for_user_page(
+    user: Principal,
+    cursor: Option<u64>,
+    limit: u64,
+    include_data: bool,
+) -> ExtendedMetadataPage {
+    let cursor = cursor.unwrap_or(0);
+    STATE.with(|state| {
+        let state = state.borrow();
+        let mut tokens = state
+            .tokens_of(&user)
+            .into_iter()
+            .filter(|&token_id| token_id >= cursor);
+        let items = tokens
+            .by_ref()
+            .take(limit.min(MAX_PAGE_SIZE) as usize)
+            .map(|token_id| {
+                let mut metadata_desc = state.metadata(token_id);
+                if !include_data {
+                    for part in &mut metadata_desc {
+                        part.data.clear();
+                    }
+                }
+                ExtendedMetadataResult {
+                    metadata_desc,
+                    token_id,
+                }
             })
             .collect();
-        call::reply((metadata,));
-    });
+        let next = tokens.next();
+        ExtendedMetadataPage { items, next }
+    })
+}
+
+#[derive(CandidType)]
+struct TokenOwner {
+    token_id: u64,
+    owner: Principal,
+}
+
+#[query(name = "listTokens")]
+fn list_tokens(start: u64, limit: u64) -> Vec<TokenOwner> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let end = start
+            .saturating_add(limit.min(MAX_TOKENS_PER_QUERY))
+            .min(state.nft_count());
+        (start..end)
+            .filter_map(|token_id| {
+                let owner = state.live_owner(token_id)?;
+                Some(TokenOwner { token_id, owner })
+            })
+            .collect()
+    })
 }
 
 // ----------------------
@@ -294,26 +474,12 @@ fn safe_transfer_from_notify(
 fn approve(user: Principal, token_id: u64) -> Result {
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        let state = &mut *state;
-        let caller = api::caller();
-        let nft = state
-            .nfts
-            .get_mut(usize::try_from(token_id)?)
-            .ok_or(Error::InvalidTokenId)?;
-        if nft.owner != caller
-            && nft.approved != Some(caller)
-            && !state
-                .operators
-                .get(&user)
-                .map(|s| s.contains(&caller))
-                .unwrap_or(false)
-            && !state.custodians.contains(&caller)
-        {
-            Err(Error::Unauthorized)
-        } else {
-            nft.approved = Some(user);
-            Ok(state.next_txid())
-        }
+        let from = state.approve(&api::caller(), user, token_id)?;
+        Ok(state.record_tx(TransactionType::Approve {
+            token_id,
+            from,
+            to: user,
+        }))
     })
 }
 
@@ -322,52 +488,67 @@ fn set_approval_for_all(operator: Principal, is_approved: bool) -> Result {
     STATE.with(|state| {
         let mut state = state.borrow_mut();
         let caller = api::caller();
-        if operator != caller {
-            let operators = state.operators.entry(caller).or_default();
-            if operator == MGMT {
-                if !is_approved {
-                    operators.clear();
-                } else {
-                    // cannot enable everyone as an operator
-                }
-            } else {
-                if is_approved {
-                    operators.insert(operator);
-                } else {
-                    operators.remove(&operator);
-                }
-            }
+        if is_approved {
+            state.check_unpaused()?;
+        }
+        let changed = if operator == caller {
+            false
+        } else if operator == MGMT {
+            // cannot enable everyone as an operator, but disabling it removes them all
+            !is_approved && state.clear_operators(&caller)
+        } else {
+            state.set_operator(&caller, &operator, is_approved)
+        };
+        // only changes are logged, so that every txid handed out refers to one
+        if !changed {
+            return Err(Error::Other);
         }
-        Ok(state.next_txid())
+        Ok(state.record_tx(TransactionType::SetApprovalForAll {
+            from: caller,
+            to: operator,
+        }))
     })
 }
 
-// #[query(name = "getApprovedDip721")] // Psychedelic/DIP721#5
-fn _get_approved(token_id: u64) -> Result<Principal> {
-    STATE.with(|state| {
-        let approved = state
-            .borrow()
-            .nfts
-            .get(usize::try_from(token_id)?)
-            .ok_or(Error::InvalidTokenId)?
-            .approved
-            .unwrap_or_else(api::caller);
-        Ok(approved)
-    })
+// None if no one is approved, rather than an error, so that it can be told apart from a bad id.
+#[query(name = "getApprovedDip721")]
+fn get_approved(token_id: u64) -> Result<Option<Principal>> {
+    STATE.with(|state| Ok(state.borrow().nft(token_id)?.approved))
 }
 
+// with the caller as the owner; see isApprovedForAll for any owner
 #[query(name = "isApprovedForAllDip721")]
 fn is_approved_for_all(operator: Principal) -> bool {
+    STATE.with(|state| state.borrow().is_operator(&api::caller(), &operator))
+}
+
+// -----------------------------
+// transaction history interface
+// -----------------------------
+
+const MAX_TRANSACTIONS_PER_QUERY: u64 = 1000;
+
+#[query(name = "getTransaction")]
+fn get_transaction(txid: u128) -> Result<TxResult> {
+    STATE.with(|state| state.borrow().transaction(txid).ok_or(Error::InvalidTxId))
+}
+
+#[query(name = "getTransactions")]
+fn get_transactions(start: u128, len: u64) -> Vec<TxResult> {
     STATE.with(|state| {
-        state
-            .borrow()
-            .operators
-            .get(&api::caller())
-            .map(|s| s.contains(&operator))
-            .unwrap_or(false)
+        let state = state.borrow();
+        let end = start
+            .saturating_add(len.min(MAX_TRANSACTIONS_PER_QUERY) as u128)
+            .min(state.transaction_count());
+        (start..end).filter_map(|txid| state.transaction(txid)).collect()
     })
 }
 
+#[query(name = "totalTransactions")]
+fn total_transactions() -> u128 {
+    STATE.with(|state| state.borrow().transaction_count())
+}
+
 // --------------
 // mint interface
 // --------------
@@ -377,23 +558,50 @@ fn mint(
     to: Principal,
     metadata: MetadataDesc,
     blob_content: Vec<u8>,
+) -> Result<MintResult, ConstrainedError> {
+    mint_nft(to, metadata, blob_content, false)
+}
+
+// Soulbound from the start, so the recipient never has a chance to pass it on; only minters can
+// do this, as with make_soulbound.
+#[update(name = "mintSoulboundDip721")]
+fn mint_soulbound(
+    to: Principal,
+    metadata: MetadataDesc,
+    blob_content: Vec<u8>,
+) -> Result<MintResult, ConstrainedError> {
+    let is_minter = STATE.with(|state| state.borrow().has_role(&api::caller(), Role::Minter));
+    if !is_minter {
+        return Err(ConstrainedError::Unauthorized);
+    }
+    mint_nft(to, metadata, blob_content, true)
+}
+
+fn mint_nft(
+    to: Principal,
+    metadata: MetadataDesc,
+    blob_content: Vec<u8>,
+    soulbound: bool,
 ) -> Result<MintResult, ConstrainedError> {
     let (txid, tkid) = STATE.with(|state| {
         let mut state = state.borrow_mut();
-        // everyone can mint
-        // if !state.custodians.contains(&api::caller()) {
-        //     return Err(ConstrainedError::Unauthorized);
-        // }
-        let new_id = state.nfts.len() as u64;
-        let nft = Nft {
-            owner: to,
-            approved: None,
-            id: new_id,
-            metadata,
-            content: blob_content,
-        };
-        state.nfts.push(nft);
-        Ok((state.next_txid(), new_id))
+        let caller = api::caller();
+        if state.paused == Some(true) {
+            return Err(ConstrainedError::Paused);
+        }
+        state.check_mint(&caller)?;
+        let metadata = uploads::resolve(&mut state, &caller, metadata)?;
+        state.count_mint(&caller);
+        let mut nft = Nft::new(to, &metadata, &blob_content);
+        nft.soulbound = soulbound;
+        let new_id = state.push_nft(&nft);
+        Ok((
+            state.record_tx(TransactionType::Mint {
+                token_id: new_id,
+                to,
+            }),
+            new_id,
+        ))
     })?;
     http::add_hash(tkid);
     Ok(MintResult {
@@ -440,45 +648,198 @@ fn simple_mint(
 // burn interface
 // --------------
 
+// The owner, the approved principal and the owner's operators can burn a token, and admins can
+// burn soulbound tokens to revoke them.
 #[update(name = "burnDip721")]
 fn burn(token_id: u64) -> Result {
-    STATE.with(|state| {
+    let txid = STATE.with(|state| {
         let mut state = state.borrow_mut();
-        let nft = state
-            .nfts
-            .get_mut(usize::try_from(token_id)?)
-            .ok_or(Error::InvalidTokenId)?;
-        if nft.owner != api::caller() {
+        let mut nft = state.live_nft(token_id)?;
+        let caller = api::caller();
+        if nft.owner != caller
+            && nft.approved != Some(caller)
+            && !state.is_operator(&nft.owner, &caller)
+            && !(state.is_soulbound(&nft) && state.has_role(&caller, Role::Admin))
+        {
             Err(Error::Unauthorized)
         } else {
-            nft.owner = MGMT;
-            Ok(state.next_txid())
+            state.check_unpaused()?;
+            let from = nft.owner;
+            state.burn(token_id, &mut nft);
+            Ok(state.record_tx(TransactionType::Burn { token_id, from }))
         }
-    })
+    })?;
+    http::add_hash(token_id);
+    Ok(txid)
 }
 
+// The heap part of the canister state, saved whole on every upgrade; the rest is in stable memory.
+// Add new fields as Options, so that the previous version's saved state still decodes.
 #[derive(CandidType, Deserialize, Default)]
 struct State {
-    nfts: Vec<Nft>,
     custodians: HashSet<Principal>,
-    operators: HashMap<Principal, HashSet<Principal>>, // owner to operators
     logo: Option<LogoResult>,
     name: String,
     symbol: String,
-    txid: u128,
+    tx_base: u128, // txid of the first entry in TRANSACTIONS
+    created_at: Option<u64>,
+    upgraded_at: Option<u64>,
+    icrc7_recent: Option<icrc7::RecentTransfers>,
+    burned: Option<u64>, // number of burned tokens; None until the burns from before it existed are counted
+    purge_burned: Option<bool>,
+    mint_policy: Option<MintPolicy>, // None is MintPolicy::Custodians
+    roles: Option<HashMap<Role, HashSet<Principal>>>, // every role but Admin, whose members are `custodians`
+    proposals: Option<proposals::Proposals>,
+    paused: Option<bool>,
+    soulbound: Option<bool>, // every token, regardless of Nft::soulbound; fixed at init
+    uploads: Option<uploads::Uploads>,
+    hashes_version: Option<u32>, // the http::HASHES_VERSION that HTTP_HASHES is complete for
 }
 
-#[derive(CandidType, Deserialize)]
+// Admins are the custodians, and have every other role as well.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+enum Role {
+    Admin,
+    Minter,
+    MetadataEditor,
+    WhiteListManager,
+    Pauser,
+}
+
+impl Role {
+    const ALL: [Role; 5] = [
+        Role::Admin,
+        Role::Minter,
+        Role::MetadataEditor,
+        Role::WhiteListManager,
+        Role::Pauser,
+    ];
+}
+
+// Who can call mintDip721 and simpleMintDip721, besides minters, who always can and have no limit.
+#[derive(CandidType, Deserialize, Clone)]
+enum MintPolicy {
+    Custodians,
+    Minters(HashSet<Principal>),
+    WhiteList(HashMap<Principal, Option<u64>>), // principal to how many tokens it can mint, if limited
+    Open { per_caller_limit: Option<u64> },
+}
+
+// An entry of NFTS. The metadata and content are blobs elsewhere in stable memory, so changing
+// the owner or the approval rewrites only this fixed-size record.
 struct Nft {
-    owner: Principal,
+    owner: Principal, // the management canister once burned
     approved: Option<Principal>,
-    id: u64,
-    metadata: MetadataDesc,
-    content: Vec<u8>,
+    metadata: Blob, // candid-encoded MetadataDesc
+    content: Blob,
+    burned: bool,
+    frozen: bool, // can't be transferred until unfrozen
+    soulbound: bool, // can never be transferred
+}
+
+impl Nft {
+    fn new(owner: Principal, metadata: &MetadataDesc, content: &[u8]) -> Self {
+        Nft {
+            owner,
+            approved: None,
+            metadata: Blob::encode(metadata),
+            content: Blob::new(content),
+            burned: false,
+            frozen: false,
+            soulbound: false,
+        }
+    }
+
+    fn metadata(&self) -> MetadataDesc {
+        self.metadata.decode()
+    }
+}
+
+impl Fixed for Nft {
+    // 96 bytes of fields, padded to leave room for more; the padding of older records reads as zero
+    const SIZE: u64 = 128;
+    fn write_to(&self, buf: &mut [u8]) {
+        self.owner.write_to(&mut buf[0..30]);
+        self.approved.write_to(&mut buf[30..61]);
+        self.metadata.write_to(&mut buf[61..77]);
+        self.content.write_to(&mut buf[77..93]);
+        buf[93] = self.burned as u8;
+        buf[94] = self.frozen as u8;
+        buf[95] = self.soulbound as u8;
+    }
+    fn read_from(buf: &[u8]) -> Self {
+        Nft {
+            owner: Principal::read_from(&buf[0..30]),
+            approved: Option::read_from(&buf[30..61]),
+            metadata: Blob::read_from(&buf[61..77]),
+            content: Blob::read_from(&buf[77..93]),
+            burned: buf[93] == 1,
+            frozen: buf[94] == 1,
+            soulbound: buf[95] == 1,
+        }
+    }
+}
+
+// The txids of the last mint, transfer, approval and burn of a token, for the DIP721 v2 token metadata.
+#[derive(Clone, Copy, Default)]
+struct TokenHistory {
+    minted: Option<u128>,
+    transferred: Option<u128>,
+    approved: Option<u128>,
+    burned: Option<u128>,
+}
+
+impl Fixed for TokenHistory {
+    // txids are stored as u64; the log would run out of stable memory long before that overflowed
+    const SIZE: u64 = 36;
+    fn write_to(&self, buf: &mut [u8]) {
+        let fields = [self.minted, self.transferred, self.approved, self.burned];
+        for (i, txid) in fields.iter().enumerate() {
+            txid.map(|txid| txid as u64).write_to(&mut buf[i * 9..i * 9 + 9]);
+        }
+    }
+    fn read_from(buf: &[u8]) -> Self {
+        let txid = |i: usize| Option::<u64>::read_from(&buf[i * 9..i * 9 + 9]).map(u128::from);
+        TokenHistory {
+            minted: txid(0),
+            transferred: txid(1),
+            approved: txid(2),
+            burned: txid(3),
+        }
+    }
+}
+
+// An ICRC-37 approval, kept apart from the DIP721 ones since it can expire.
+#[derive(Clone, Copy)]
+struct Approval {
+    expires_at: Option<u64>,
+    created_at_time: u64,
+    memo: Blob,
+}
+
+impl Approval {
+    fn is_live(&self, now: u64) -> bool {
+        self.expires_at.map_or(true, |expires_at| expires_at > now)
+    }
+}
+
+impl Fixed for Approval {
+    const SIZE: u64 = 33;
+    fn write_to(&self, buf: &mut [u8]) {
+        self.expires_at.write_to(&mut buf[0..9]);
+        self.created_at_time.write_to(&mut buf[9..17]);
+        self.memo.write_to(&mut buf[17..33]);
+    }
+    fn read_from(buf: &[u8]) -> Self {
+        Approval {
+            expires_at: Option::read_from(&buf[0..9]),
+            created_at_time: u64::read_from(&buf[9..17]),
+            memo: Blob::read_from(&buf[17..33]),
+        }
+    }
 }
 
 type MetadataDesc = Vec<MetadataPart>;
-type MetadataDescRef<'a> = &'a [MetadataPart];
 
 #[derive(CandidType, Deserialize, Debug)]
 struct MetadataPart {
@@ -511,12 +872,456 @@ enum MetadataVal {
     Nat64Content(u64),
 }
 
+#[derive(CandidType, Deserialize, Clone)]
+struct TxResult {
+    fee: u128,
+    caller: Principal,
+    timestamp: u64,
+    transaction_type: TransactionType,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+enum TransactionType {
+    Transfer {
+        token_id: u64,
+        from: Principal,
+        to: Principal,
+    },
+    TransferFrom {
+        token_id: u64,
+        from: Principal,
+        to: Principal,
+    },
+    Approve {
+        token_id: u64,
+        from: Principal,
+        to: Principal,
+    },
+    SetApprovalForAll {
+        from: Principal,
+        to: Principal,
+    },
+    // an ICRC-37 token approval being revoked; `to` is the management canister when all of them are
+    Revoke {
+        token_id: u64,
+        from: Principal,
+        to: Principal,
+    },
+    Mint {
+        token_id: u64,
+        to: Principal,
+    },
+    Burn {
+        token_id: u64,
+        from: Principal,
+    },
+}
+
+impl TransactionType {
+    fn token_id(&self) -> Option<u64> {
+        match *self {
+            TransactionType::Transfer { token_id, .. }
+            | TransactionType::TransferFrom { token_id, .. }
+            | TransactionType::Approve { token_id, .. }
+            | TransactionType::Revoke { token_id, .. }
+            | TransactionType::Mint { token_id, .. }
+            | TransactionType::Burn { token_id, .. } => Some(token_id),
+            TransactionType::SetApprovalForAll { .. } => None,
+        }
+    }
+}
+
+// The stable structures are only touched through State, so that the STATE borrow
+// still tells reads (&self) from writes (&mut self).
 impl State {
-    fn next_txid(&mut self) -> u128 {
-        let txid = self.txid;
-        self.txid += 1;
+    fn nft(&self, token_id: u64) -> Result<Nft> {
+        NFTS.get(token_id).ok_or(Error::InvalidTokenId)
+    }
+
+    fn metadata(&self, token_id: u64) -> MetadataDesc {
+        NFTS.get(token_id)
+            .map(|nft| nft.metadata())
+            .unwrap_or_default()
+    }
+
+    fn nft_count(&self) -> u64 {
+        NFTS.len()
+    }
+
+    fn push_nft(&mut self, nft: &Nft) -> u64 {
+        let token_id = NFTS.push(nft);
+        OWNERS.insert(&nft.owner, &token_id);
+        if let Some(approved) = nft.approved {
+            APPROVALS.insert(&approved, &token_id);
+        }
+        token_id
+    }
+
+    // a token that exists and hasn't been burned
+    fn live_nft(&self, token_id: u64) -> Result<Nft> {
+        let nft = self.nft(token_id)?;
+        if nft.burned {
+            Err(Error::InvalidTokenId)
+        } else {
+            Ok(nft)
+        }
+    }
+
+    // Err if no one can transfer the token, because it is soulbound, because of a pause or because
+    // it is frozen. Forced transfers by proposal are exempt from the last two.
+    fn check_transferable(&self, nft: &Nft) -> Result<()> {
+        if self.is_soulbound(nft) {
+            return Err(Error::Soulbound);
+        }
+        self.check_unpaused()?;
+        if nft.frozen {
+            Err(Error::TokenFrozen)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn is_soulbound(&self, nft: &Nft) -> bool {
+        nft.soulbound || self.soulbound == Some(true)
+    }
+
+    fn check_unpaused(&self) -> Result<()> {
+        if self.paused == Some(true) {
+            Err(Error::Paused)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn live_owner(&self, token_id: u64) -> Option<Principal> {
+        Some(self.live_nft(token_id).ok()?.owner)
+    }
+
+    // tokens that exist and haven't been burned
+    fn supply(&self) -> u64 {
+        self.nft_count() - self.burned.unwrap_or(0)
+    }
+
+    fn update_nft(&mut self, token_id: u64, nft: &Nft) {
+        NFTS.set(token_id, nft);
+    }
+
+    fn balance_of(&self, user: &Principal) -> u64 {
+        OWNERS.len(user)
+    }
+
+    // in ascending order
+    fn tokens_of(&self, user: &Principal) -> Vec<u64> {
+        let mut tokens: Vec<_> = OWNERS.iter(user).collect();
+        tokens.sort_unstable();
+        tokens
+    }
+
+    // moves a token to its new owner and clears its approvals; authorization is up to the caller
+    fn transfer(&mut self, token_id: u64, nft: &mut Nft, to: Principal) {
+        let from = nft.owner;
+        self.set_approved(token_id, nft, None);
+        self.clear_token_approvals(token_id);
+        nft.owner = to;
+        self.update_nft(token_id, nft);
+        self.move_token(token_id, from, to);
+        http::update_owner(self, token_id);
+    }
+
+    // Burned tokens are dropped from the owner index rather than moved to the burn address.
+    // Authorization is up to the caller.
+    fn burn(&mut self, token_id: u64, nft: &mut Nft) {
+        self.set_approved(token_id, nft, None);
+        self.clear_token_approvals(token_id);
+        OWNERS.remove(&nft.owner, &token_id);
+        nft.owner = MGMT;
+        nft.burned = true;
+        if self.purge_burned == Some(true) {
+            // stable memory is never freed, but the token no longer refers to its data
+            let mut metadata = nft.metadata();
+            for part in &mut metadata {
+                part.data.clear();
+            }
+            nft.metadata = Blob::encode(&metadata);
+            nft.content = Blob::default();
+        }
+        self.update_nft(token_id, nft);
+        *self.burned.get_or_insert(0) += 1;
+    }
+
+    // Before burns were tracked, burning moved a token to the burn address. Those tokens are
+    // marked burned by the first upgrade that finds them uncounted, which returns their ids.
+    fn mark_legacy_burns(&mut self) -> Vec<u64> {
+        if self.burned.is_some() {
+            return vec![];
+        }
+        let tokens: Vec<_> = OWNERS.iter(&MGMT).collect();
+        for &token_id in &tokens {
+            if let Ok(mut nft) = self.nft(token_id) {
+                nft.burned = true;
+                self.update_nft(token_id, &nft);
+            }
+        }
+        OWNERS.clear(&MGMT);
+        self.burned = Some(tokens.len() as u64);
+        tokens
+    }
+
+    fn has_role(&self, user: &Principal, role: Role) -> bool {
+        self.custodians.contains(user)
+            || self
+                .roles
+                .as_ref()
+                .and_then(|roles| roles.get(&role))
+                .map_or(false, |members| members.contains(user))
+    }
+
+    fn proposal_threshold(&self) -> u64 {
+        self.proposals.as_ref().map_or(1, |proposals| proposals.threshold())
+    }
+
+    // an admin who can use the powers that proposals are for without making one
+    fn acts_alone(&self, user: &Principal) -> bool {
+        self.has_role(user, Role::Admin) && self.proposal_threshold() == 1
+    }
+
+    fn role_members(&self, role: Role) -> HashSet<Principal> {
+        if role == Role::Admin {
+            return self.custodians.clone();
+        }
+        self.roles
+            .as_ref()
+            .and_then(|roles| roles.get(&role))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn set_role(&mut self, user: Principal, role: Role, granted: bool) -> Result<()> {
+        if role == Role::Admin {
+            if granted {
+                self.custodians.insert(user);
+            } else if self.custodians.len() == 1 && self.custodians.contains(&user) {
+                return Err(Error::LastAdmin);
+            } else {
+                self.custodians.remove(&user);
+            }
+        } else {
+            let members = self
+                .roles
+                .get_or_insert_with(HashMap::new)
+                .entry(role)
+                .or_default();
+            if granted {
+                members.insert(user);
+            } else {
+                members.remove(&user);
+            }
+        }
+        Ok(())
+    }
+
+    fn check_mint(&self, caller: &Principal) -> Result<(), ConstrainedError> {
+        if self.has_role(caller, Role::Minter) {
+            return Ok(());
+        }
+        let limit = match self.mint_policy {
+            None | Some(MintPolicy::Custodians) => return Err(ConstrainedError::Unauthorized),
+            Some(MintPolicy::Minters(ref minters)) if minters.contains(caller) => return Ok(()),
+            Some(MintPolicy::Minters(_)) => return Err(ConstrainedError::Unauthorized),
+            Some(MintPolicy::WhiteList(ref white_list)) => *white_list
+                .get(caller)
+                .ok_or(ConstrainedError::Unauthorized)?,
+            Some(MintPolicy::Open { per_caller_limit }) => per_caller_limit,
+        };
+        match limit {
+            Some(limit) if self.mint_count(caller) >= limit => Err(ConstrainedError::QuotaExceeded),
+            _ => Ok(()),
+        }
+    }
+
+    fn mint_count(&self, caller: &Principal) -> u64 {
+        MINT_COUNTS.get(caller).unwrap_or(0)
+    }
+
+    fn count_mint(&mut self, caller: &Principal) {
+        MINT_COUNTS.insert(caller, &(self.mint_count(caller) + 1));
+    }
+
+    fn move_token(&mut self, token_id: u64, from: Principal, to: Principal) {
+        OWNERS.remove(&from, &token_id);
+        OWNERS.insert(&to, &token_id);
+    }
+
+    // for the interfaces that have no separate field for the logo's type
+    fn logo_data_uri(&self) -> String {
+        let logo = self.logo.as_ref().unwrap_or(&DEFAULT_LOGO);
+        format!("data:{};base64,{}", logo.logo_type, logo.data)
+    }
+
+    // owners other than the burn address
+    fn holder_count(&self) -> u64 {
+        OWNERS.key_count() - u64::from(OWNERS.len(&MGMT) > 0)
+    }
+
+    // keeps APPROVALS in step with Nft::approved; the caller still writes the Nft back
+    fn set_approved(&mut self, token_id: u64, nft: &mut Nft, approved: Option<Principal>) {
+        if let Some(old) = nft.approved {
+            APPROVALS.remove(&old, &token_id);
+        }
+        if let Some(new) = approved {
+            APPROVALS.insert(&new, &token_id);
+        }
+        nft.approved = approved;
+    }
+
+    // The owner, the owner's operators and admins acting alone can approve someone for a token;
+    // the principal approved for it can't pass the approval on. Returns the owner.
+    fn approve(&mut self, caller: &Principal, user: Principal, token_id: u64) -> Result<Principal> {
+        let mut nft = self.live_nft(token_id)?;
+        let owner = nft.owner;
+        if owner != *caller && !self.is_operator(&owner, caller) && !self.acts_alone(caller) {
+            return Err(Error::Unauthorized);
+        }
+        if self.is_soulbound(&nft) {
+            return Err(Error::Soulbound);
+        }
+        self.check_unpaused()?;
+        self.set_approved(token_id, &mut nft, Some(user));
+        self.update_nft(token_id, &nft);
+        Ok(owner)
+    }
+
+    // in ascending order
+    fn approved_tokens(&self, user: &Principal) -> Vec<u64> {
+        let mut tokens: Vec<_> = APPROVALS.iter(user).collect();
+        tokens.sort_unstable();
+        tokens
+    }
+
+    fn history(&self, token_id: u64) -> TokenHistory {
+        HISTORY.get(token_id).unwrap_or_default()
+    }
+
+    // ICRC-37 approvals, expired ones included
+    fn token_approvals(&self, token_id: u64) -> Vec<(Principal, Approval)> {
+        TOKEN_SPENDERS
+            .iter(&token_id)
+            .filter_map(|spender| Some((spender, TOKEN_APPROVALS.get(&(token_id, spender))?)))
+            .collect()
+    }
+
+    fn token_approval(&self, token_id: u64, spender: Principal) -> Option<Approval> {
+        TOKEN_APPROVALS.get(&(token_id, spender))
+    }
+
+    fn set_token_approval(&mut self, token_id: u64, spender: Principal, approval: Option<Approval>) {
+        match approval {
+            Some(approval) => {
+                TOKEN_SPENDERS.insert(&token_id, &spender);
+                TOKEN_APPROVALS.insert(&(token_id, spender), &approval);
+            }
+            None => {
+                TOKEN_SPENDERS.remove(&token_id, &spender);
+                TOKEN_APPROVALS.remove(&(token_id, spender));
+            }
+        }
+    }
+
+    fn clear_token_approvals(&mut self, token_id: u64) {
+        for (spender, _) in self.token_approvals(token_id) {
+            self.set_token_approval(token_id, spender, None);
+        }
+    }
+
+    fn collection_approvals(&self, owner: Principal) -> Vec<(Principal, Approval)> {
+        COLLECTION_SPENDERS
+            .iter(&owner)
+            .filter_map(|spender| Some((spender, COLLECTION_APPROVALS.get(&(owner, spender))?)))
+            .collect()
+    }
+
+    fn collection_approval(&self, owner: Principal, spender: Principal) -> Option<Approval> {
+        COLLECTION_APPROVALS.get(&(owner, spender))
+    }
+
+    fn set_collection_approval(
+        &mut self,
+        owner: Principal,
+        spender: Principal,
+        approval: Option<Approval>,
+    ) {
+        match approval {
+            Some(approval) => {
+                COLLECTION_SPENDERS.insert(&owner, &spender);
+                COLLECTION_APPROVALS.insert(&(owner, spender), &approval);
+            }
+            None => {
+                COLLECTION_SPENDERS.remove(&owner, &spender);
+                COLLECTION_APPROVALS.remove(&(owner, spender));
+            }
+        }
+    }
+
+    fn is_operator(&self, owner: &Principal, operator: &Principal) -> bool {
+        OPERATORS.contains(owner, operator)
+    }
+
+    // false if `operator` already was or wasn't one of the owner's operators
+    fn set_operator(&mut self, owner: &Principal, operator: &Principal, approved: bool) -> bool {
+        if approved {
+            OPERATORS.insert(owner, operator)
+        } else {
+            OPERATORS.remove(owner, operator)
+        }
+    }
+
+    // false if the owner had no operators
+    fn clear_operators(&mut self, owner: &Principal) -> bool {
+        let had_any = OPERATORS.len(owner) > 0;
+        OPERATORS.clear(owner);
+        had_any
+    }
+
+    // every state-changing call is logged, and its index in the log is the txid handed back to the caller
+    fn record_tx(&mut self, transaction_type: TransactionType) -> u128 {
+        let tx = TxResult {
+            fee: 0,
+            caller: api::caller(),
+            timestamp: api::time(),
+            transaction_type,
+        };
+        let txid = self.tx_base + TRANSACTIONS.push(&Blob::encode(&tx)) as u128;
+        if let Some(token_id) = tx.transaction_type.token_id() {
+            // tokens from before HISTORY existed get empty entries to keep the indexes aligned
+            while HISTORY.len() <= token_id {
+                HISTORY.push(&TokenHistory::default());
+            }
+            let mut history = self.history(token_id);
+            let last = match tx.transaction_type {
+                TransactionType::Mint { .. } => &mut history.minted,
+                TransactionType::Transfer { .. } | TransactionType::TransferFrom { .. } => {
+                    &mut history.transferred
+                }
+                TransactionType::Approve { .. } => &mut history.approved,
+                TransactionType::Burn { .. } => &mut history.burned,
+                // revoking doesn't change when the token was last approved
+                TransactionType::Revoke { .. } => return txid,
+                TransactionType::SetApprovalForAll { .. } => unreachable!(),
+            };
+            *last = Some(txid);
+            HISTORY.set(token_id, &history);
+        }
         txid
     }
+
+    fn transaction(&self, txid: u128) -> Option<TxResult> {
+        let index = u64::try_from(txid.checked_sub(self.tx_base)?).ok()?;
+        TRANSACTIONS.get(index).map(|tx| tx.decode())
+    }
+
+    fn transaction_count(&self) -> u128 {
+        self.tx_base + TRANSACTIONS.len() as u128
+    }
 }
 
 #[derive(CandidType, Deserialize)]
@@ -526,11 +1331,16 @@ enum InterfaceId {
     Mint,
     Burn,
     TransferNotification,
+    Pause,
+    Freeze,
 }
 
 #[derive(CandidType, Deserialize)]
 enum ConstrainedError {
     Unauthorized,
+    QuotaExceeded,
+    Paused,
+    InvalidUpload,
     // InvalidUri,
 }
 
@@ -538,8 +1348,9 @@ enum ConstrainedError {
 fn set_name(name: String) -> Result<()> {
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        if state.custodians.contains(&api::caller()) {
+        if state.has_role(&api::caller(), Role::MetadataEditor) {
             state.name = name;
+            http::update_collection(&state);
             Ok(())
         } else {
             Err(Error::Unauthorized)
@@ -551,8 +1362,110 @@ fn set_name(name: String) -> Result<()> {
 fn set_symbol(sym: String) -> Result<()> {
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        if state.custodians.contains(&api::caller()) {
+        if state.has_role(&api::caller(), Role::MetadataEditor) {
             state.symbol = sym;
+            http::update_collection(&state);
+            Ok(())
+        } else {
+            Err(Error::Unauthorized)
+        }
+    })
+}
+
+#[update]
+fn set_mint_policy(policy: MintPolicy) -> Result<()> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.acts_alone(&api::caller()) {
+            state.mint_policy = Some(policy);
+            Ok(())
+        } else {
+            Err(Error::Unauthorized)
+        }
+    })
+}
+
+#[query]
+fn mint_policy() -> MintPolicy {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .mint_policy
+            .clone()
+            .unwrap_or(MintPolicy::Custodians)
+    })
+}
+
+// While paused, no one can transfer, mint or burn tokens, or grant approvals; revoking them still works.
+#[update]
+fn set_paused(paused: bool) -> Result<()> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.has_role(&api::caller(), Role::Pauser) {
+            state.paused = Some(paused);
+            Ok(())
+        } else {
+            Err(Error::Unauthorized)
+        }
+    })
+}
+
+#[query]
+fn is_paused() -> bool {
+    STATE.with(|state| state.borrow().paused == Some(true))
+}
+
+#[update]
+fn set_frozen(token_id: u64, frozen: bool) -> Result<()> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if !state.has_role(&api::caller(), Role::Pauser) {
+            return Err(Error::Unauthorized);
+        }
+        let mut nft = state.live_nft(token_id)?;
+        nft.frozen = frozen;
+        state.update_nft(token_id, &nft);
+        Ok(())
+    })
+}
+
+#[query]
+fn is_frozen(token_id: u64) -> Result<bool> {
+    STATE.with(|state| Ok(state.borrow().live_nft(token_id)?.frozen))
+}
+
+// There is no way back, so that holders can rely on a soulbound token staying with them.
+#[update]
+fn make_soulbound(token_id: u64) -> Result<()> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if !state.has_role(&api::caller(), Role::Minter) {
+            return Err(Error::Unauthorized);
+        }
+        let mut nft = state.live_nft(token_id)?;
+        nft.soulbound = true;
+        state.set_approved(token_id, &mut nft, None);
+        state.clear_token_approvals(token_id);
+        state.update_nft(token_id, &nft);
+        Ok(())
+    })
+}
+
+#[query]
+fn is_soulbound(token_id: u64) -> Result<bool> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        Ok(state.is_soulbound(&state.live_nft(token_id)?))
+    })
+}
+
+// applies to tokens burned from then on
+#[update]
+fn set_purge_burned(purge: bool) -> Result<()> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.acts_alone(&api::caller()) {
+            state.purge_burned = Some(purge);
             Ok(())
         } else {
             Err(Error::Unauthorized)
@@ -564,8 +1477,9 @@ fn set_symbol(sym: String) -> Result<()> {
 fn set_logo(logo: Option<LogoResult>) -> Result<()> {
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        if state.custodians.contains(&api::caller()) {
+        if state.has_role(&api::caller(), Role::MetadataEditor) {
             state.logo = logo;
+            http::update_collection(&state);
             Ok(())
         } else {
             Err(Error::Unauthorized)
@@ -577,13 +1491,8 @@ fn set_logo(logo: Option<LogoResult>) -> Result<()> {
 fn set_custodian(user: Principal, custodian: bool) -> Result<()> {
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        if state.custodians.contains(&api::caller()) {
-            if custodian {
-                state.custodians.insert(user);
-            } else {
-                state.custodians.remove(&user);
-            }
-            Ok(())
+        if state.acts_alone(&api::caller()) {
+            state.set_role(user, Role::Admin, custodian)
         } else {
             Err(Error::Unauthorized)
         }
@@ -594,3 +1503,183 @@ fn set_custodian(user: Principal, custodian: bool) -> Result<()> {
 fn is_custodian(principal: Principal) -> bool {
     STATE.with(|state| state.borrow().custodians.contains(&principal))
 }
+
+#[update]
+fn grant_role(user: Principal, role: Role) -> Result<()> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.acts_alone(&api::caller()) {
+            state.set_role(user, role, true)
+        } else {
+            Err(Error::Unauthorized)
+        }
+    })
+}
+
+#[update]
+fn revoke_role(user: Principal, role: Role) -> Result<()> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.acts_alone(&api::caller()) {
+            state.set_role(user, role, false)
+        } else {
+            Err(Error::Unauthorized)
+        }
+    })
+}
+
+// only the roles granted to the user; admins also have every other role
+#[query]
+fn roles_of(user: Principal) -> Vec<Role> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        Role::ALL
+            .iter()
+            .copied()
+            .filter(|&role| state.role_members(role).contains(&user))
+            .collect()
+    })
+}
+
+#[query]
+fn role_members(role: Role) -> Vec<Principal> {
+    STATE.with(|state| state.borrow().role_members(role).into_iter().collect())
+}
+
+#[derive(CandidType, Deserialize)]
+struct WhiteListEntry {
+    principal: Principal,
+    max_mint: Option<u64>,
+}
+
+// for editing a WhiteList mint policy without replacing it; adding a listed principal replaces its max_mint
+#[update]
+fn add_to_white_list(entries: Vec<WhiteListEntry>) -> Result<()> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if !state.has_role(&api::caller(), Role::WhiteListManager) {
+            return Err(Error::Unauthorized);
+        }
+        match state.mint_policy {
+            Some(MintPolicy::WhiteList(ref mut white_list)) => {
+                for entry in entries {
+                    white_list.insert(entry.principal, entry.max_mint);
+                }
+                Ok(())
+            }
+            _ => Err(Error::Other),
+        }
+    })
+}
+
+#[update]
+fn remove_from_white_list(users: Vec<Principal>) -> Result<()> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if !state.has_role(&api::caller(), Role::WhiteListManager) {
+            return Err(Error::Unauthorized);
+        }
+        match state.mint_policy {
+            Some(MintPolicy::WhiteList(ref mut white_list)) => {
+                for user in &users {
+                    white_list.remove(user);
+                }
+                Ok(())
+            }
+            _ => Err(Error::Other),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADMIN: Principal = Principal::from_slice(&[1]);
+    const OWNER: Principal = Principal::from_slice(&[2]);
+    const OPERATOR: Principal = Principal::from_slice(&[3]);
+    const SPENDER: Principal = Principal::from_slice(&[4]);
+    const STRANGER: Principal = Principal::from_slice(&[5]);
+
+    // token 0, owned by OWNER, who has made OPERATOR an operator
+    fn setup() {
+        stable::initialize();
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            state.custodians = HashSet::from_iter([ADMIN]);
+            state.push_nft(&Nft::new(OWNER, &MetadataDesc::new(), &[]));
+            state.set_operator(&OWNER, &OPERATOR, true);
+        });
+    }
+
+    fn approve_as(caller: Principal, user: Principal) -> Result<Principal> {
+        STATE.with(|state| state.borrow_mut().approve(&caller, user, 0))
+    }
+
+    #[test]
+    fn no_approval_is_none() {
+        setup();
+        assert!(matches!(get_approved(0), Ok(None)));
+        assert!(matches!(get_approved(1), Err(Error::InvalidTokenId)));
+    }
+
+    #[test]
+    fn owner_approves() {
+        setup();
+        assert!(matches!(approve_as(OWNER, SPENDER), Ok(owner) if owner == OWNER));
+        assert!(matches!(get_approved(0), Ok(Some(user)) if user == SPENDER));
+        assert_eq!(
+            STATE.with(|state| state.borrow().approved_tokens(&SPENDER)),
+            vec![0]
+        );
+    }
+
+    #[test]
+    fn operator_approves_for_owner() {
+        setup();
+        assert!(approve_as(OPERATOR, SPENDER).is_ok());
+        assert!(matches!(get_approved(0), Ok(Some(user)) if user == SPENDER));
+        // approving someone else replaces the approval
+        assert!(approve_as(OPERATOR, STRANGER).is_ok());
+        assert!(matches!(get_approved(0), Ok(Some(user)) if user == STRANGER));
+        assert!(STATE.with(|state| state.borrow().approved_tokens(&SPENDER).is_empty()));
+    }
+
+    #[test]
+    fn others_cannot_approve() {
+        setup();
+        assert!(matches!(
+            approve_as(STRANGER, STRANGER),
+            Err(Error::Unauthorized)
+        ));
+        assert!(approve_as(OWNER, SPENDER).is_ok());
+        // the approved principal can't pass the approval on, or re-approve itself
+        assert!(matches!(
+            approve_as(SPENDER, STRANGER),
+            Err(Error::Unauthorized)
+        ));
+        assert!(matches!(
+            approve_as(SPENDER, SPENDER),
+            Err(Error::Unauthorized)
+        ));
+        assert!(matches!(get_approved(0), Ok(Some(user)) if user == SPENDER));
+        // an admin can, while a single admin is enough to act
+        assert!(approve_as(ADMIN, STRANGER).is_ok());
+    }
+
+    #[test]
+    fn operators_are_per_owner() {
+        setup();
+        STATE.with(|state| {
+            let state = state.borrow();
+            assert!(state.is_operator(&OWNER, &OPERATOR));
+            assert!(!state.is_operator(&OPERATOR, &OWNER));
+            assert!(!state.is_operator(&OWNER, &STRANGER));
+        });
+        STATE.with(|state| state.borrow_mut().set_operator(&OWNER, &OPERATOR, false));
+        assert!(matches!(
+            approve_as(OPERATOR, SPENDER),
+            Err(Error::Unauthorized)
+        ));
+    }
+}
@@ -0,0 +1,223 @@
+// k-of-n approval for the admins' most sensitive powers. While the threshold is 1, every admin
+// still has those powers directly, and a proposal is executed as soon as it is made. From 2 on,
+// the direct endpoints refuse admins and proposals are the only way.
+
+use candid::{CandidType, Principal};
+use ic_cdk::{api, export::candid};
+
+use crate::{Error, MintPolicy, Result, Role, State, TransactionType, MGMT, STATE};
+
+const DEFAULT_LIFETIME: u64 = 7 * 24 * 60 * 60 * 1_000_000_000; // nanoseconds
+const MAX_PROPOSALS_PER_QUERY: u64 = 100;
+
+#[derive(CandidType, Deserialize, Clone)]
+enum ProposalAction {
+    SetRole {
+        user: Principal,
+        role: Role,
+        granted: bool,
+    },
+    // moves a token regardless of who owns it or has approved what
+    ForceTransfer {
+        token_id: u64,
+        to: Principal,
+    },
+    SetMintPolicy(MintPolicy),
+    SetPurgeBurned(bool),
+    SetThreshold(u64),
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+enum ProposalStatus {
+    Open,
+    Executed,
+    Failed(Error),
+    Expired,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+struct Proposal {
+    id: u64,
+    proposer: Principal,
+    action: ProposalAction,
+    approvals: Vec<Principal>,
+    created_at: u64,
+    expires_at: u64,
+    status: ProposalStatus,
+}
+
+#[derive(CandidType, Deserialize, Default)]
+pub struct Proposals {
+    threshold: u64,
+    proposals: Vec<Proposal>, // by id
+}
+
+impl Proposals {
+    pub fn new(threshold: u64) -> Self {
+        Proposals {
+            threshold: threshold.max(1),
+            proposals: vec![],
+        }
+    }
+
+    pub fn threshold(&self) -> u64 {
+        self.threshold.max(1)
+    }
+}
+
+// Approvals from principals that are no longer admins don't count.
+fn approve(state: &mut State, id: u64, caller: Principal) -> Result<Proposal> {
+    let now = api::time();
+    let threshold = state.proposal_threshold();
+    let proposals = state.proposals.get_or_insert_with(Default::default);
+    let proposal = proposals
+        .proposals
+        .get_mut(id as usize)
+        .ok_or(Error::InvalidProposalId)?;
+    if !matches!(proposal.status, ProposalStatus::Open) {
+        return Err(Error::Other);
+    }
+    if now >= proposal.expires_at {
+        proposal.status = ProposalStatus::Expired;
+        return Ok(proposal.clone());
+    }
+    if !proposal.approvals.contains(&caller) {
+        proposal.approvals.push(caller);
+    }
+    let approvals = proposal.approvals.clone();
+    let action = proposal.action.clone();
+    let approved = approvals
+        .iter()
+        .filter(|&approver| state.custodians.contains(approver))
+        .count() as u64;
+    if approved >= threshold {
+        let status = match execute(state, action) {
+            Ok(()) => ProposalStatus::Executed,
+            Err(e) => ProposalStatus::Failed(e),
+        };
+        state.proposals.as_mut().unwrap().proposals[id as usize].status = status;
+    }
+    Ok(state.proposals.as_ref().unwrap().proposals[id as usize].clone())
+}
+
+fn execute(state: &mut State, action: ProposalAction) -> Result<()> {
+    match action {
+        ProposalAction::SetRole {
+            user,
+            role,
+            granted,
+        } => {
+            // the admins left must still be able to reach the threshold
+            if role == Role::Admin
+                && !granted
+                && state.custodians.contains(&user)
+                && (state.custodians.len() as u64) <= state.proposal_threshold()
+            {
+                return Err(Error::LastAdmin);
+            }
+            state.set_role(user, role, granted)
+        }
+        ProposalAction::ForceTransfer { token_id, to } => {
+            if to == MGMT {
+                return Err(Error::ZeroAddress);
+            }
+            let mut nft = state.live_nft(token_id)?;
+            if state.is_soulbound(&nft) {
+                return Err(Error::Soulbound);
+            }
+            let from = nft.owner;
+            state.transfer(token_id, &mut nft, to);
+            state.record_tx(TransactionType::TransferFrom { token_id, from, to });
+            Ok(())
+        }
+        ProposalAction::SetMintPolicy(policy) => {
+            state.mint_policy = Some(policy);
+            Ok(())
+        }
+        ProposalAction::SetPurgeBurned(purge) => {
+            state.purge_burned = Some(purge);
+            Ok(())
+        }
+        ProposalAction::SetThreshold(threshold) => {
+            if threshold == 0 || threshold > state.custodians.len() as u64 {
+                return Err(Error::Other);
+            }
+            state
+                .proposals
+                .get_or_insert_with(Default::default)
+                .threshold = threshold;
+            Ok(())
+        }
+    }
+}
+
+// The proposer's approval is counted right away. `expires_at` is in nanoseconds, and defaults to a week from now.
+#[update]
+fn propose(action: ProposalAction, expires_at: Option<u64>) -> Result<Proposal> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let caller = api::caller();
+        if !state.custodians.contains(&caller) {
+            return Err(Error::Unauthorized);
+        }
+        let now = api::time();
+        let proposals = state.proposals.get_or_insert_with(Default::default);
+        let id = proposals.proposals.len() as u64;
+        proposals.proposals.push(Proposal {
+            id,
+            proposer: caller,
+            action,
+            approvals: vec![],
+            created_at: now,
+            expires_at: expires_at.unwrap_or(now + DEFAULT_LIFETIME),
+            status: ProposalStatus::Open,
+        });
+        approve(&mut state, id, caller)
+    })
+}
+
+#[update]
+fn approve_proposal(id: u64) -> Result<Proposal> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let caller = api::caller();
+        if !state.custodians.contains(&caller) {
+            return Err(Error::Unauthorized);
+        }
+        approve(&mut state, id, caller)
+    })
+}
+
+#[query]
+fn proposal(id: u64) -> Option<Proposal> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        state
+            .proposals
+            .as_ref()?
+            .proposals
+            .get(id as usize)
+            .cloned()
+    })
+}
+
+// Proposals are only marked expired when someone tries to approve them, so check `expires_at` too.
+#[query]
+fn proposals(start: u64, limit: u64) -> Vec<Proposal> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        state
+            .proposals
+            .iter()
+            .flat_map(|proposals| &proposals.proposals)
+            .skip(start as usize)
+            .take(limit.min(MAX_PROPOSALS_PER_QUERY) as usize)
+            .cloned()
+            .collect()
+    })
+}
+
+#[query]
+fn proposal_threshold() -> u64 {
+    STATE.with(|state| state.borrow().proposal_threshold())
+}
@@ -0,0 +1,809 @@
+#[cfg(test)]
+use std::cell::RefCell;
+use std::convert::TryInto;
+use std::marker::PhantomData;
+use std::result::Result as StdResult;
+
+use candid::{de::IDLDeserialize, CandidType, Decode, Encode, Principal};
+use ic_cdk::{api, export::candid};
+use ic_certified_map::Hash;
+use serde::de::DeserializeOwned;
+
+// Everything in here is read and written in place in stable memory, so none of it has to be
+// serialized in pre_upgrade. Space is handed out by a bump allocator and never freed; structures
+// that outgrow their space move and leave the old space behind, which is at most as much again as
+// they take up now, and churn in a map is cleaned up in its own table.
+//
+// The first page holds the layout header:
+//   [0, 8)    MAGIC
+//   [8, 16)   the next free byte
+//   [16, 32)  the heap state saved by the last pre_upgrade
+//   [32, 40)  the space set aside for it
+//   [64, ..)  one 32-byte header per structure, addressed by slot number
+
+const WASM_PAGE_SIZE: u64 = 65536;
+const MAGIC: &[u8; 8] = b"DIP721S1";
+const NEXT_FREE: u64 = 8;
+const SAVED_STATE: u64 = 16;
+const SAVED_STATE_CAPACITY: u64 = 32;
+const HEADERS: u64 = 64;
+const HEADER_SIZE: u64 = 32;
+
+// The stable memory API, so that everything in here can run against a plain Vec<u8> in tests.
+trait Memory {
+    fn size(&self) -> u64; // in pages
+    fn grow(&self, pages: u64) -> bool; // false if there is no more to be had
+    fn read(&self, offset: u64, buf: &mut [u8]);
+    fn write(&self, offset: u64, buf: &[u8]);
+}
+
+#[cfg(not(test))]
+struct CanisterMemory;
+
+#[cfg(not(test))]
+impl Memory for CanisterMemory {
+    fn size(&self) -> u64 {
+        api::stable::stable64_size()
+    }
+    fn grow(&self, pages: u64) -> bool {
+        api::stable::stable64_grow(pages).is_ok()
+    }
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        api::stable::stable64_read(offset, buf);
+    }
+    fn write(&self, offset: u64, buf: &[u8]) {
+        api::stable::stable64_write(offset, buf);
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    // one per test, since each runs on its own thread
+    static VEC_MEMORY: RefCell<Vec<u8>> = RefCell::new(vec![]);
+}
+
+#[cfg(test)]
+struct VecMemory;
+
+#[cfg(test)]
+impl Memory for VecMemory {
+    fn size(&self) -> u64 {
+        VEC_MEMORY.with(|memory| memory.borrow().len() as u64 / WASM_PAGE_SIZE)
+    }
+    fn grow(&self, pages: u64) -> bool {
+        VEC_MEMORY.with(|memory| {
+            let mut memory = memory.borrow_mut();
+            let len = memory.len() + (pages * WASM_PAGE_SIZE) as usize;
+            memory.resize(len, 0);
+        });
+        true
+    }
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        VEC_MEMORY.with(|memory| {
+            let start = offset as usize;
+            buf.copy_from_slice(&memory.borrow()[start..start + buf.len()]);
+        });
+    }
+    fn write(&self, offset: u64, buf: &[u8]) {
+        VEC_MEMORY.with(|memory| {
+            let start = offset as usize;
+            memory.borrow_mut()[start..start + buf.len()].copy_from_slice(buf);
+        });
+    }
+}
+
+#[cfg(not(test))]
+const MEMORY: CanisterMemory = CanisterMemory;
+#[cfg(test)]
+const MEMORY: VecMemory = VecMemory;
+
+pub fn is_initialized() -> bool {
+    if MEMORY.size() == 0 {
+        return false;
+    }
+    let mut magic = [0; 8];
+    read(0, &mut magic);
+    &magic == MAGIC
+}
+
+// Lays out an empty store over whatever was in stable memory before. Allocation starts past the
+// old contents, which are left behind, since freshly allocated space is expected to be zeroed.
+pub fn initialize() {
+    let end = (MEMORY.size() * WASM_PAGE_SIZE).max(WASM_PAGE_SIZE);
+    write(0, &[0; WASM_PAGE_SIZE as usize]);
+    write(0, MAGIC);
+    write_u64(NEXT_FREE, end);
+}
+
+// What storage::stable_save left in stable memory before this layout: the candid encoding of its
+// arguments, followed by zeroes up to the end of the last page. This decodes the first one.
+pub fn restore_legacy<T: CandidType + DeserializeOwned>() -> StdResult<T, String> {
+    let mut bytes = vec![0; (MEMORY.size() * WASM_PAGE_SIZE) as usize];
+    read(0, &mut bytes);
+    let mut de = IDLDeserialize::new(&bytes).map_err(|e| e.to_string())?;
+    de.get_value().map_err(|e| e.to_string())
+}
+
+// The heap state is small, so it is simply re-encoded on every upgrade, over the last one while it
+// still fits.
+pub fn save_state<T: CandidType>(state: &T) {
+    let bytes = Encode!(state).unwrap();
+    let len = bytes.len() as u64;
+    let mut saved = read_fixed::<Blob>(SAVED_STATE);
+    let capacity = read_u64(SAVED_STATE_CAPACITY);
+    if len > capacity {
+        saved.offset = alloc(2 * len);
+        write_u64(SAVED_STATE_CAPACITY, 2 * len);
+    }
+    saved.len = len;
+    write(saved.offset, &bytes);
+    write_fixed(SAVED_STATE, &saved);
+}
+
+pub fn restore_state<T: CandidType + DeserializeOwned>() -> T {
+    read_fixed::<Blob>(SAVED_STATE).decode()
+}
+
+fn ensure_size(end: u64) {
+    let size = MEMORY.size() * WASM_PAGE_SIZE;
+    if end > size {
+        let pages = (end - size + WASM_PAGE_SIZE - 1) / WASM_PAGE_SIZE;
+        if !MEMORY.grow(pages) {
+            api::trap("out of stable memory");
+        }
+    }
+}
+
+fn read(offset: u64, buf: &mut [u8]) {
+    MEMORY.read(offset, buf);
+}
+
+fn write(offset: u64, buf: &[u8]) {
+    ensure_size(offset + buf.len() as u64);
+    MEMORY.write(offset, buf);
+}
+
+fn read_u64(offset: u64) -> u64 {
+    let mut buf = [0; 8];
+    read(offset, &mut buf);
+    u64::from_le_bytes(buf)
+}
+
+fn write_u64(offset: u64, value: u64) {
+    write(offset, &value.to_le_bytes());
+}
+
+fn read_fixed<T: Fixed>(offset: u64) -> T {
+    let mut buf = vec![0; T::SIZE as usize];
+    read(offset, &mut buf);
+    T::read_from(&buf)
+}
+
+fn write_fixed<T: Fixed>(offset: u64, value: &T) {
+    write(offset, &to_bytes(value));
+}
+
+fn to_bytes<T: Fixed>(value: &T) -> Vec<u8> {
+    let mut buf = vec![0; T::SIZE as usize];
+    value.write_to(&mut buf);
+    buf
+}
+
+// Memory past the allocation pointer has never been written, so it is always zeroed.
+fn alloc(len: u64) -> u64 {
+    let offset = read_u64(NEXT_FREE);
+    ensure_size(offset + len);
+    write_u64(NEXT_FREE, offset + len);
+    offset
+}
+
+// Values with a fixed-size encoding, which can be overwritten in place.
+pub trait Fixed: Sized {
+    const SIZE: u64;
+    fn write_to(&self, buf: &mut [u8]);
+    fn read_from(buf: &[u8]) -> Self;
+}
+
+impl Fixed for u64 {
+    const SIZE: u64 = 8;
+    fn write_to(&self, buf: &mut [u8]) {
+        buf[..8].copy_from_slice(&self.to_le_bytes());
+    }
+    fn read_from(buf: &[u8]) -> Self {
+        u64::from_le_bytes(buf[..8].try_into().unwrap())
+    }
+}
+
+impl Fixed for Principal {
+    // a length byte, then up to 29 bytes of principal
+    const SIZE: u64 = 30;
+    fn write_to(&self, buf: &mut [u8]) {
+        let bytes = self.as_slice();
+        buf[0] = bytes.len() as u8;
+        buf[1..1 + bytes.len()].copy_from_slice(bytes);
+    }
+    fn read_from(buf: &[u8]) -> Self {
+        Principal::from_slice(&buf[1..1 + buf[0] as usize])
+    }
+}
+
+impl Fixed for Hash {
+    const SIZE: u64 = 32;
+    fn write_to(&self, buf: &mut [u8]) {
+        buf[..32].copy_from_slice(self);
+    }
+    fn read_from(buf: &[u8]) -> Self {
+        buf[..32].try_into().unwrap()
+    }
+}
+
+impl<T: Fixed> Fixed for Option<T> {
+    const SIZE: u64 = 1 + T::SIZE;
+    fn write_to(&self, buf: &mut [u8]) {
+        match self {
+            Some(value) => {
+                buf[0] = 1;
+                value.write_to(&mut buf[1..]);
+            }
+            None => buf[0] = 0,
+        }
+    }
+    fn read_from(buf: &[u8]) -> Self {
+        if buf[0] == 1 {
+            Some(T::read_from(&buf[1..]))
+        } else {
+            None
+        }
+    }
+}
+
+impl<A: Fixed, B: Fixed> Fixed for (A, B) {
+    const SIZE: u64 = A::SIZE + B::SIZE;
+    fn write_to(&self, buf: &mut [u8]) {
+        self.0.write_to(&mut buf[..A::SIZE as usize]);
+        self.1.write_to(&mut buf[A::SIZE as usize..]);
+    }
+    fn read_from(buf: &[u8]) -> Self {
+        (
+            A::read_from(&buf[..A::SIZE as usize]),
+            B::read_from(&buf[A::SIZE as usize..]),
+        )
+    }
+}
+
+// An immutable byte string somewhere in stable memory.
+#[derive(CandidType, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+pub struct Blob {
+    offset: u64,
+    len: u64,
+}
+
+impl Blob {
+    pub fn new(bytes: &[u8]) -> Self {
+        if bytes.is_empty() {
+            return Self::default();
+        }
+        let offset = alloc(bytes.len() as u64);
+        write(offset, bytes);
+        Blob {
+            offset,
+            len: bytes.len() as u64,
+        }
+    }
+
+    pub fn encode<T: CandidType>(value: &T) -> Self {
+        Self::new(&Encode!(value).unwrap())
+    }
+
+    pub fn read(self) -> Vec<u8> {
+        let mut buf = vec![0; self.len as usize];
+        if !buf.is_empty() {
+            read(self.offset, &mut buf);
+        }
+        buf
+    }
+
+    pub fn decode<T: CandidType + DeserializeOwned>(self) -> T {
+        Decode!(&self.read(), T).unwrap()
+    }
+}
+
+impl Fixed for Blob {
+    const SIZE: u64 = 16;
+    fn write_to(&self, buf: &mut [u8]) {
+        self.offset.write_to(&mut buf[..8]);
+        self.len.write_to(&mut buf[8..]);
+    }
+    fn read_from(buf: &[u8]) -> Self {
+        Blob {
+            offset: u64::read_from(&buf[..8]),
+            len: u64::read_from(&buf[8..]),
+        }
+    }
+}
+
+// A growable array. Elements are stored in chunks of CHUNK_LEN, found through a directory that is
+// allocated along with the vector, so pushing never moves existing elements.
+// header: length, directory offset
+const CHUNK_LEN: u64 = 4096;
+const DIRECTORY_LEN: u64 = 65536;
+
+pub struct StableVec<T> {
+    header: u64,
+    _marker: PhantomData<T>,
+}
+
+impl<T> StableVec<T> {
+    pub const fn new(slot: u64) -> Self {
+        StableVec {
+            header: HEADERS + slot * HEADER_SIZE,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: Fixed> StableVec<T> {
+    pub fn len(&self) -> u64 {
+        read_u64(self.header)
+    }
+
+    pub fn get(&self, index: u64) -> Option<T> {
+        if index < self.len() {
+            Some(read_fixed(self.element(index)))
+        } else {
+            None
+        }
+    }
+
+    pub fn set(&self, index: u64, value: &T) {
+        assert!(index < self.len(), "index out of bounds");
+        write_fixed(self.element(index), value);
+    }
+
+    pub fn push(&self, value: &T) -> u64 {
+        let index = self.len();
+        write_fixed(self.element(index), value);
+        write_u64(self.header, index + 1);
+        index
+    }
+
+    // the offset of the element at `index`, allocating the directory and its chunk on first use
+    fn element(&self, index: u64) -> u64 {
+        let mut directory = read_u64(self.header + 8);
+        if directory == 0 {
+            directory = alloc(DIRECTORY_LEN * 8);
+            write_u64(self.header + 8, directory);
+        }
+        let chunk_index = index / CHUNK_LEN;
+        if chunk_index >= DIRECTORY_LEN {
+            api::trap("stable vector is full");
+        }
+        let entry = directory + chunk_index * 8;
+        let mut chunk = read_u64(entry);
+        if chunk == 0 {
+            chunk = alloc(CHUNK_LEN * T::SIZE);
+            write_u64(entry, chunk);
+        }
+        chunk + index % CHUNK_LEN * T::SIZE
+    }
+}
+
+// An open-addressing hash map with linear probing. Each bucket is a state byte followed by the
+// encoded key and value; keys are compared by their encoding.
+// header: length, capacity, table offset, deleted buckets
+const EMPTY: u8 = 0;
+const FULL: u8 = 1;
+const DELETED: u8 = 2;
+const MIN_CAPACITY: u64 = 64;
+
+pub struct StableMap<K, V> {
+    header: u64,
+    _marker: PhantomData<(K, V)>,
+}
+
+// not derived, because derive would require K and V to be Copy too
+impl<K, V> Clone for StableMap<K, V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K, V> Copy for StableMap<K, V> {}
+
+impl<K, V> StableMap<K, V> {
+    pub const fn new(slot: u64) -> Self {
+        StableMap {
+            header: HEADERS + slot * HEADER_SIZE,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<K: Fixed, V: Fixed> StableMap<K, V> {
+    pub fn len(&self) -> u64 {
+        read_u64(self.header)
+    }
+
+    fn capacity(&self) -> u64 {
+        read_u64(self.header + 8)
+    }
+
+    fn table(&self) -> u64 {
+        read_u64(self.header + 16)
+    }
+
+    fn deleted(&self) -> u64 {
+        read_u64(self.header + 24)
+    }
+
+    fn bucket_size() -> u64 {
+        1 + K::SIZE + V::SIZE
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        let bucket = self.find(&to_bytes(key)).ok()?;
+        Some(read_fixed(bucket + 1 + K::SIZE))
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.find(&to_bytes(key)).is_ok()
+    }
+
+    pub fn insert(&self, key: &K, value: &V) -> Option<V> {
+        self.reserve_one();
+        let key = to_bytes(key);
+        match self.find(&key) {
+            Ok(bucket) => {
+                let old = read_fixed(bucket + 1 + K::SIZE);
+                write_fixed(bucket + 1 + K::SIZE, value);
+                Some(old)
+            }
+            Err(bucket) => {
+                let mut state = [0];
+                read(bucket, &mut state);
+                if state[0] == DELETED {
+                    write_u64(self.header + 24, self.deleted() - 1);
+                }
+                let mut buf = vec![FULL];
+                buf.extend_from_slice(&key);
+                buf.extend_from_slice(&to_bytes(value));
+                write(bucket, &buf);
+                write_u64(self.header, self.len() + 1);
+                None
+            }
+        }
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        let bucket = self.find(&to_bytes(key)).ok()?;
+        let old = read_fixed(bucket + 1 + K::SIZE);
+        write(bucket, &[DELETED]);
+        write_u64(self.header, self.len() - 1);
+        write_u64(self.header + 24, self.deleted() + 1);
+        Some(old)
+    }
+
+    // in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (K, V)> {
+        let (table, bucket_size) = (self.table(), Self::bucket_size());
+        (0..self.capacity()).filter_map(move |i| {
+            let mut buf = vec![0; bucket_size as usize];
+            read(table + i * bucket_size, &mut buf);
+            if buf[0] == FULL {
+                let (key, value) = buf[1..].split_at(K::SIZE as usize);
+                Some((K::read_from(key), V::read_from(value)))
+            } else {
+                None
+            }
+        })
+    }
+
+    fn find(&self, key: &[u8]) -> StdResult<u64, u64> {
+        let capacity = self.capacity();
+        if capacity == 0 {
+            return Err(0);
+        }
+        probe(self.table(), capacity, Self::bucket_size(), key)
+    }
+
+    // Keeps at least a quarter of the buckets empty after the next insert, so probing always ends.
+    // When the table is mostly deleted buckets, it is rehashed in place instead of grown, so that
+    // inserting and removing keys forever doesn't use up stable memory.
+    fn reserve_one(&self) {
+        let (len, capacity) = (self.len(), self.capacity());
+        if (len + self.deleted() + 1) * 4 <= capacity * 3 {
+            return;
+        }
+        let new_capacity = if (len + 1) * 2 <= capacity {
+            capacity
+        } else {
+            (capacity * 2).max(MIN_CAPACITY)
+        };
+        let bucket_size = Self::bucket_size();
+        let old_table = self.table();
+        let mut old = vec![0; (capacity * bucket_size) as usize];
+        read(old_table, &mut old);
+        let table = if new_capacity == capacity {
+            write(old_table, &vec![0; old.len()]);
+            old_table
+        } else {
+            alloc(new_capacity * bucket_size)
+        };
+        for buf in old.chunks(bucket_size as usize) {
+            if buf[0] == FULL {
+                let key = &buf[1..1 + K::SIZE as usize];
+                if let Err(bucket) = probe(table, new_capacity, bucket_size, key) {
+                    write(bucket, buf);
+                }
+            }
+        }
+        write_u64(self.header + 8, new_capacity);
+        write_u64(self.header + 16, table);
+        write_u64(self.header + 24, 0);
+    }
+}
+
+// Ok with the bucket holding `key`, or Err with the bucket an insert of `key` should use.
+fn probe(table: u64, capacity: u64, bucket_size: u64, key: &[u8]) -> StdResult<u64, u64> {
+    let mut index = fnv1a(key) % capacity;
+    let mut first_deleted = None;
+    let mut buf = vec![0; 1 + key.len()];
+    loop {
+        let bucket = table + index * bucket_size;
+        read(bucket, &mut buf);
+        match buf[0] {
+            EMPTY => return Err(first_deleted.unwrap_or(bucket)),
+            FULL if buf[1..] == *key => return Ok(bucket),
+            DELETED if first_deleted.is_none() => first_deleted = Some(bucket),
+            _ => {}
+        }
+        index = (index + 1) % capacity;
+    }
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// A map from keys to sets of values, kept as three maps: the number of values for each key, each
+// key's values by position, and the position of each value. Removing a value moves its key's last
+// value into the hole, so every operation is a handful of map lookups.
+pub struct StableSetMap<K, V> {
+    counts: StableMap<K, u64>,
+    values: StableMap<(K, u64), V>,
+    positions: StableMap<(K, V), u64>,
+}
+
+impl<K, V> StableSetMap<K, V> {
+    // uses slots `slot` to `slot + 2`
+    pub const fn new(slot: u64) -> Self {
+        StableSetMap {
+            counts: StableMap::new(slot),
+            values: StableMap::new(slot + 1),
+            positions: StableMap::new(slot + 2),
+        }
+    }
+}
+
+impl<K: Fixed + Copy, V: Fixed + Copy> StableSetMap<K, V> {
+    pub fn len(&self, key: &K) -> u64 {
+        self.counts.get(key).unwrap_or(0)
+    }
+
+    // the number of keys with at least one value
+    pub fn key_count(&self) -> u64 {
+        self.counts.len()
+    }
+
+    pub fn contains(&self, key: &K, value: &V) -> bool {
+        self.positions.contains_key(&(*key, *value))
+    }
+
+    pub fn insert(&self, key: &K, value: &V) -> bool {
+        if self.contains(key, value) {
+            return false;
+        }
+        let len = self.len(key);
+        self.values.insert(&(*key, len), value);
+        self.positions.insert(&(*key, *value), &len);
+        self.counts.insert(key, &(len + 1));
+        true
+    }
+
+    pub fn remove(&self, key: &K, value: &V) -> bool {
+        let position = match self.positions.remove(&(*key, *value)) {
+            Some(position) => position,
+            None => return false,
+        };
+        let last = self.len(key) - 1;
+        if position != last {
+            if let Some(moved) = self.values.get(&(*key, last)) {
+                self.values.insert(&(*key, position), &moved);
+                self.positions.insert(&(*key, moved), &position);
+            }
+        }
+        self.values.remove(&(*key, last));
+        if last == 0 {
+            self.counts.remove(key);
+        } else {
+            self.counts.insert(key, &last);
+        }
+        true
+    }
+
+    // in no particular order
+    pub fn iter(&self, key: &K) -> impl Iterator<Item = V> {
+        let (key, values) = (*key, self.values);
+        (0..self.len(&key)).filter_map(move |i| values.get(&(key, i)))
+    }
+
+    pub fn clear(&self, key: &K) {
+        let values: Vec<V> = self.iter(key).collect();
+        for value in &values {
+            self.remove(key, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VEC: StableVec<u64> = StableVec::new(0);
+    const MAP: StableMap<u64, u64> = StableMap::new(1);
+    const SET_MAP: StableSetMap<u64, u64> = StableSetMap::new(2); // slots 2-4
+
+    #[test]
+    fn vec_spans_chunks() {
+        initialize();
+        let len = CHUNK_LEN + 10;
+        for i in 0..len {
+            assert_eq!(VEC.push(&(i * 3)), i);
+        }
+        assert_eq!(VEC.len(), len);
+        assert_eq!(VEC.get(CHUNK_LEN - 1), Some((CHUNK_LEN - 1) * 3));
+        assert_eq!(VEC.get(CHUNK_LEN), Some(CHUNK_LEN * 3));
+        assert_eq!(VEC.get(len), None);
+        VEC.set(CHUNK_LEN + 5, &7);
+        assert_eq!(VEC.get(CHUNK_LEN + 5), Some(7));
+    }
+
+    #[test]
+    fn map_insert_get_remove() {
+        initialize();
+        assert_eq!(MAP.get(&1), None);
+        assert_eq!(MAP.insert(&1, &10), None);
+        assert_eq!(MAP.insert(&2, &20), None);
+        assert_eq!(MAP.insert(&1, &11), Some(10));
+        assert_eq!(MAP.len(), 2);
+        assert_eq!(MAP.get(&1), Some(11));
+        assert_eq!(MAP.remove(&1), Some(11));
+        assert_eq!(MAP.remove(&1), None);
+        assert!(!MAP.contains_key(&1));
+        assert_eq!(MAP.get(&2), Some(20));
+        assert_eq!(MAP.len(), 1);
+    }
+
+    #[test]
+    fn map_grows() {
+        initialize();
+        for i in 0..1000 {
+            MAP.insert(&i, &(i + 1));
+        }
+        assert_eq!(MAP.len(), 1000);
+        assert!(MAP.capacity() * 3 >= 1000 * 4);
+        for i in 0..1000 {
+            assert_eq!(MAP.get(&i), Some(i + 1));
+        }
+        let mut entries: Vec<_> = MAP.iter().collect();
+        entries.sort_unstable();
+        assert_eq!(entries, (0..1000).map(|i| (i, i + 1)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn map_reuses_deleted_buckets() {
+        initialize();
+        MAP.insert(&1, &1);
+        MAP.remove(&1);
+        assert_eq!(MAP.deleted(), 1);
+        MAP.insert(&1, &2);
+        assert_eq!(MAP.deleted(), 0);
+        assert_eq!(MAP.get(&1), Some(2));
+        // churning through many keys rehashes in place instead of growing
+        for i in 2..10_000 {
+            MAP.insert(&i, &i);
+            MAP.remove(&i);
+        }
+        assert_eq!(MAP.capacity(), MIN_CAPACITY);
+        assert_eq!(MAP.len(), 1);
+        assert_eq!(MAP.get(&1), Some(2));
+    }
+
+    #[test]
+    fn map_churn_stays_in_its_table() {
+        initialize();
+        for i in 0..1000 {
+            MAP.insert(&i, &i);
+        }
+        let (next_free, pages) = (read_u64(NEXT_FREE), MEMORY.size());
+        // every round replaces all the keys, as transfers and approvals do
+        for round in 0..50 {
+            for i in 0..1000 {
+                MAP.remove(&(round * 1000 + i));
+                MAP.insert(&((round + 1) * 1000 + i), &i);
+            }
+        }
+        assert_eq!(read_u64(NEXT_FREE), next_free);
+        assert_eq!(MEMORY.size(), pages);
+        assert_eq!(MAP.len(), 1000);
+        assert_eq!(MAP.get(&50_999), Some(999));
+    }
+
+    #[test]
+    fn set_map_insert_remove() {
+        initialize();
+        assert!(SET_MAP.insert(&1, &10));
+        assert!(SET_MAP.insert(&1, &11));
+        assert!(SET_MAP.insert(&1, &12));
+        assert!(!SET_MAP.insert(&1, &11));
+        assert!(SET_MAP.insert(&2, &10));
+        assert_eq!(SET_MAP.len(&1), 3);
+        assert_eq!(SET_MAP.key_count(), 2);
+        // the last value moves into the hole
+        assert!(SET_MAP.remove(&1, &10));
+        assert!(!SET_MAP.remove(&1, &10));
+        assert!(!SET_MAP.contains(&1, &10));
+        let mut values: Vec<_> = SET_MAP.iter(&1).collect();
+        values.sort_unstable();
+        assert_eq!(values, vec![11, 12]);
+        SET_MAP.clear(&1);
+        assert_eq!(SET_MAP.len(&1), 0);
+        assert_eq!(SET_MAP.iter(&1).count(), 0);
+        assert_eq!(SET_MAP.key_count(), 1);
+        assert!(SET_MAP.contains(&2, &10));
+    }
+
+    #[test]
+    fn blob_round_trip() {
+        initialize();
+        assert_eq!(Blob::new(&[]).read(), Vec::<u8>::new());
+        let bytes: Vec<u8> = (0..200_000).map(|i| i as u8).collect();
+        assert_eq!(Blob::new(&bytes).read(), bytes);
+        let text = "some metadata".to_string();
+        assert_eq!(Blob::encode(&text).decode::<String>(), text);
+    }
+
+    #[test]
+    fn saved_state_reuses_its_space() {
+        initialize();
+        save_state(&vec![1u64; 10]);
+        let next_free = read_u64(NEXT_FREE);
+        save_state(&vec![2u64; 15]);
+        assert_eq!(read_u64(NEXT_FREE), next_free);
+        assert_eq!(restore_state::<Vec<u64>>(), vec![2; 15]);
+        save_state(&vec![3u64; 1000]);
+        assert!(read_u64(NEXT_FREE) > next_free);
+        assert_eq!(restore_state::<Vec<u64>>(), vec![3; 1000]);
+    }
+
+    #[test]
+    fn legacy_image_is_decoded_then_left_behind() {
+        // what storage::stable_save wrote: its arguments, then zeroes to the end of the page
+        let legacy = "x".repeat(150_000);
+        write(0, &Encode!(&legacy, &5u64).unwrap());
+        assert!(!is_initialized());
+        assert_eq!(restore_legacy::<String>().unwrap(), legacy);
+        initialize();
+        assert!(is_initialized());
+        assert_eq!(read_u64(NEXT_FREE), 3 * WASM_PAGE_SIZE);
+        // none of the old image is mistaken for table buckets or vector elements
+        for i in 0..100 {
+            MAP.insert(&i, &i);
+            VEC.push(&i);
+        }
+        assert_eq!(MAP.iter().count(), 100);
+        assert_eq!(VEC.get(99), Some(99));
+    }
+}
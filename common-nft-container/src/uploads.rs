@@ -0,0 +1,246 @@
+// Staged uploads, for metadata part data too large to fit in a single mintDip721 call. The uploader
+// sends the data in chunks, commits it with its SHA-256, and then mints with a part whose `data` is
+// empty and whose key-value data has an "uploadId" holding the upload's id, which mint replaces
+// with the uploaded bytes. The chunks are written to stable memory as they arrive, and only where
+// they are is kept on the heap. That space is never reclaimed, even when an upload is cancelled or
+// expires after a day, so uploads are bounded in size.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use candid::{CandidType, Principal};
+use ic_cdk::{api, export::candid};
+use sha2::{Digest, Sha256};
+
+use crate::stable::Blob;
+use crate::{ConstrainedError, Error, MetadataDesc, MetadataVal, Result, Role, State, STATE};
+
+const UPLOAD_ID_KEY: &str = "uploadId";
+const MAX_UPLOAD_SIZE: u64 = 32 * 1024 * 1024;
+const MAX_PENDING_SIZE: u64 = 128 * 1024 * 1024; // across all uploads
+const MAX_PENDING_SIZE_PER_CALLER: u64 = 64 * 1024 * 1024;
+const MAX_UPLOADS_PER_CALLER: usize = 4;
+const UPLOAD_LIFETIME: u64 = 24 * 60 * 60 * 1_000_000_000; // nanoseconds
+
+#[derive(CandidType, Deserialize)]
+struct Upload {
+    owner: Principal,
+    created_at: u64,
+    chunks: BTreeMap<u64, Blob>,
+    size: u64,
+    committed: bool,
+}
+
+#[derive(CandidType, Deserialize, Default)]
+pub struct Uploads {
+    next_id: u64,
+    uploads: HashMap<u64, Upload>,
+}
+
+impl Uploads {
+    fn prune(&mut self, now: u64) {
+        self.uploads
+            .retain(|_, upload| now < upload.created_at.saturating_add(UPLOAD_LIFETIME));
+    }
+
+    fn pending_size(&self) -> u64 {
+        self.uploads.values().map(|upload| upload.size).sum()
+    }
+
+    fn pending_size_of(&self, owner: &Principal) -> u64 {
+        self.uploads
+            .values()
+            .filter(|upload| upload.owner == *owner)
+            .map(|upload| upload.size)
+            .sum()
+    }
+
+    fn get_mut(&mut self, upload_id: u64, caller: &Principal) -> Result<&mut Upload> {
+        match self.uploads.get_mut(&upload_id) {
+            Some(upload) if upload.owner == *caller => Ok(upload),
+            Some(_) => Err(Error::Unauthorized),
+            None => Err(Error::InvalidUploadId),
+        }
+    }
+}
+
+#[derive(CandidType)]
+struct UploadInfo {
+    upload_id: u64,
+    created_at: u64,
+    expires_at: u64,
+    chunks: u64,
+    size: u64,
+    committed: bool,
+}
+
+// Replaces the upload references in `metadata` with the data of the caller's committed uploads,
+// which are used up. Nothing is used up unless every reference is valid.
+pub(crate) fn resolve(
+    state: &mut State,
+    caller: &Principal,
+    mut metadata: MetadataDesc,
+) -> Result<MetadataDesc, ConstrainedError> {
+    let uploads = state.uploads.get_or_insert_with(Default::default);
+    uploads.prune(api::time());
+    let mut ids = HashSet::new();
+    for part in &metadata {
+        let id = match part.key_val_data.get(UPLOAD_ID_KEY) {
+            None => continue,
+            Some(MetadataVal::Nat64Content(id)) if part.data.is_empty() => *id,
+            Some(_) => return Err(ConstrainedError::InvalidUpload),
+        };
+        let usable = matches!(
+            uploads.uploads.get(&id),
+            Some(upload) if upload.owner == *caller && upload.committed
+        );
+        if !usable || !ids.insert(id) {
+            return Err(ConstrainedError::InvalidUpload);
+        }
+    }
+    for part in &mut metadata {
+        if let Some(MetadataVal::Nat64Content(id)) = part.key_val_data.remove(UPLOAD_ID_KEY) {
+            let upload = uploads.uploads.remove(&id).unwrap();
+            part.data = Vec::with_capacity(upload.size as usize);
+            for chunk in upload.chunks.values() {
+                part.data.extend_from_slice(&chunk.read());
+            }
+        }
+    }
+    Ok(metadata)
+}
+
+// ----------------
+// upload interface
+// ----------------
+
+// Uploads take stable memory that is never given back, so only minters can upload, even when the
+// mint policy lets anyone mint.
+#[update]
+fn create_upload() -> Result<u64> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let caller = api::caller();
+        if !state.has_role(&caller, Role::Minter) {
+            return Err(Error::Unauthorized);
+        }
+        let now = api::time();
+        let uploads = state.uploads.get_or_insert_with(Default::default);
+        uploads.prune(now);
+        let open = uploads
+            .uploads
+            .values()
+            .filter(|upload| upload.owner == caller)
+            .count();
+        if open >= MAX_UPLOADS_PER_CALLER {
+            return Err(Error::UploadQuotaExceeded);
+        }
+        let upload_id = uploads.next_id;
+        uploads.next_id += 1;
+        uploads.uploads.insert(
+            upload_id,
+            Upload {
+                owner: caller,
+                created_at: now,
+                chunks: BTreeMap::new(),
+                size: 0,
+                committed: false,
+            },
+        );
+        Ok(upload_id)
+    })
+}
+
+// Chunks can arrive in any order, but each index only once, since the space of a chunk that was
+// replaced could not be reclaimed.
+#[update]
+fn upload_chunk(upload_id: u64, index: u64, bytes: Vec<u8>) -> Result<()> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let uploads = state.uploads.get_or_insert_with(Default::default);
+        uploads.prune(api::time());
+        let caller = api::caller();
+        let pending = uploads.pending_size();
+        let pending_of_caller = uploads.pending_size_of(&caller);
+        let upload = uploads.get_mut(upload_id, &caller)?;
+        if upload.committed || upload.chunks.contains_key(&index) {
+            return Err(Error::Other);
+        }
+        let len = bytes.len() as u64;
+        if upload.size + len > MAX_UPLOAD_SIZE
+            || pending_of_caller + len > MAX_PENDING_SIZE_PER_CALLER
+            || pending + len > MAX_PENDING_SIZE
+        {
+            return Err(Error::UploadQuotaExceeded);
+        }
+        upload.chunks.insert(index, Blob::new(&bytes));
+        upload.size += len;
+        Ok(())
+    })
+}
+
+// The chunks must be numbered 0 to n - 1, and together hash to `sha256`.
+#[update]
+fn commit_upload(upload_id: u64, sha256: Vec<u8>) -> Result<()> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let uploads = state.uploads.get_or_insert_with(Default::default);
+        uploads.prune(api::time());
+        let upload = uploads.get_mut(upload_id, &api::caller())?;
+        if upload.committed
+            || upload
+                .chunks
+                .keys()
+                .copied()
+                .ne(0..upload.chunks.len() as u64)
+        {
+            return Err(Error::Other);
+        }
+        let mut hasher = Sha256::new();
+        for chunk in upload.chunks.values() {
+            hasher.update(chunk.read());
+        }
+        if sha256 != hasher.finalize().as_slice() {
+            return Err(Error::HashMismatch);
+        }
+        upload.committed = true;
+        Ok(())
+    })
+}
+
+#[update]
+fn cancel_upload(upload_id: u64) -> Result<()> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let uploads = state.uploads.get_or_insert_with(Default::default);
+        uploads.get_mut(upload_id, &api::caller())?;
+        uploads.uploads.remove(&upload_id);
+        Ok(())
+    })
+}
+
+// the caller's uploads that haven't expired or been minted yet
+#[query]
+fn uploads() -> Vec<UploadInfo> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let caller = api::caller();
+        let now = api::time();
+        let mut infos: Vec<_> = state
+            .uploads
+            .iter()
+            .flat_map(|uploads| &uploads.uploads)
+            .filter(|(_, upload)| upload.owner == caller)
+            .map(|(&upload_id, upload)| UploadInfo {
+                upload_id,
+                created_at: upload.created_at,
+                expires_at: upload.created_at.saturating_add(UPLOAD_LIFETIME),
+                chunks: upload.chunks.len() as u64,
+                size: upload.size,
+                committed: upload.committed,
+            })
+            .filter(|info| now < info.expires_at)
+            .collect();
+        infos.sort_by_key(|info| info.upload_id);
+        infos
+    })
+}
@@ -0,0 +1,615 @@
+// DIP721 v2, served from the same State as the v1 `*Dip721` methods. v2 token identifiers are
+// nats where v1 uses nat64, and every v1 token is visible here under the same number.
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::num::TryFromIntError;
+use std::result::Result as StdResult;
+
+use candid::{CandidType, Principal};
+use ic_cdk::{api, export::candid};
+
+use crate::{
+    Error, MetadataPart, MetadataPurpose, MetadataVal, Role, State, TransactionType, TxResult,
+    MGMT, STATE,
+};
+
+#[derive(CandidType, Deserialize)]
+enum NftError {
+    UnauthorizedOwner,
+    UnauthorizedOperator,
+    OwnerNotFound,
+    OperatorNotFound,
+    TokenNotFound,
+    ExistedNFT,
+    SelfApprove,
+    SelfTransfer,
+    TxNotFound,
+    Other(String),
+}
+
+impl From<TryFromIntError> for NftError {
+    fn from(_: TryFromIntError) -> Self {
+        Self::TokenNotFound
+    }
+}
+
+impl From<Error> for NftError {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Unauthorized => Self::UnauthorizedOperator,
+            Error::InvalidTokenId => Self::TokenNotFound,
+            Error::InvalidTxId => Self::TxNotFound,
+            Error::ZeroAddress => Self::Other("ZeroAddress".to_string()),
+            Error::LastAdmin => Self::Other("LastAdmin".to_string()),
+            Error::InvalidProposalId => Self::Other("InvalidProposalId".to_string()),
+            Error::Paused => Self::Other("Paused".to_string()),
+            Error::TokenFrozen => Self::Other("TokenFrozen".to_string()),
+            Error::Soulbound => Self::Other("Soulbound".to_string()),
+            Error::InvalidUploadId => Self::Other("InvalidUploadId".to_string()),
+            Error::HashMismatch => Self::Other("HashMismatch".to_string()),
+            Error::UploadQuotaExceeded => Self::Other("UploadQuotaExceeded".to_string()),
+            Error::Other => Self::Other("Other".to_string()),
+        }
+    }
+}
+
+type Result<T = u128, E = NftError> = StdResult<T, E>;
+
+#[allow(clippy::enum_variant_names)]
+#[derive(CandidType, Deserialize, Clone)]
+enum GenericValue {
+    BoolContent(bool),
+    TextContent(String),
+    BlobContent(Vec<u8>),
+    Principal(Principal),
+    Nat8Content(u8),
+    Nat16Content(u16),
+    Nat32Content(u32),
+    Nat64Content(u64),
+    NatContent(u128),
+    Int8Content(i8),
+    Int16Content(i16),
+    Int32Content(i32),
+    Int64Content(i64),
+    IntContent(i128),
+    FloatContent(f64),
+    NestedContent(Vec<(String, GenericValue)>),
+}
+
+impl From<&MetadataVal> for GenericValue {
+    fn from(val: &MetadataVal) -> Self {
+        match *val {
+            MetadataVal::TextContent(ref s) => Self::TextContent(s.clone()),
+            MetadataVal::BlobContent(ref b) => Self::BlobContent(b.clone()),
+            MetadataVal::NatContent(n) => Self::NatContent(n),
+            MetadataVal::Nat8Content(n) => Self::Nat8Content(n),
+            MetadataVal::Nat16Content(n) => Self::Nat16Content(n),
+            MetadataVal::Nat32Content(n) => Self::Nat32Content(n),
+            MetadataVal::Nat64Content(n) => Self::Nat64Content(n),
+        }
+    }
+}
+
+// v1 metadata only has the unsigned and text/blob kinds
+impl TryFrom<GenericValue> for MetadataVal {
+    type Error = GenericValue;
+    fn try_from(val: GenericValue) -> StdResult<Self, GenericValue> {
+        match val {
+            GenericValue::TextContent(s) => Ok(Self::TextContent(s)),
+            GenericValue::BlobContent(b) => Ok(Self::BlobContent(b)),
+            GenericValue::NatContent(n) => Ok(Self::NatContent(n)),
+            GenericValue::Nat8Content(n) => Ok(Self::Nat8Content(n)),
+            GenericValue::Nat16Content(n) => Ok(Self::Nat16Content(n)),
+            GenericValue::Nat32Content(n) => Ok(Self::Nat32Content(n)),
+            GenericValue::Nat64Content(n) => Ok(Self::Nat64Content(n)),
+            other => Err(other),
+        }
+    }
+}
+
+// A token's v2 properties are the key-value data of the part served at /<nft>:
+// the first rendered part, or the first part if none is rendered.
+fn properties(metadata: &[MetadataPart]) -> Vec<(String, GenericValue)> {
+    let part = metadata
+        .iter()
+        .find(|part| part.purpose == MetadataPurpose::Rendered)
+        .or_else(|| metadata.get(0));
+    let mut properties: Vec<_> = part
+        .into_iter()
+        .flat_map(|part| &part.key_val_data)
+        .map(|(key, val)| (key.clone(), val.into()))
+        .collect();
+    properties.sort_by(|a, b| a.0.cmp(&b.0));
+    properties
+}
+
+#[derive(CandidType)]
+struct Metadata {
+    logo: Option<String>,
+    name: Option<String>,
+    created_at: u64,
+    upgraded_at: u64,
+    custodians: Vec<Principal>,
+    symbol: Option<String>,
+}
+
+#[derive(CandidType)]
+struct Stats {
+    cycles: u128,
+    total_transactions: u128,
+    total_unique_holders: u128,
+    total_supply: u128,
+}
+
+#[derive(CandidType)]
+struct TokenMetadata {
+    token_identifier: u128,
+    owner: Option<Principal>,
+    operator: Option<Principal>,
+    is_burned: bool,
+    properties: Vec<(String, GenericValue)>,
+    minted_at: u64,
+    minted_by: Principal,
+    transferred_at: Option<u64>,
+    transferred_by: Option<Principal>,
+    approved_at: Option<u64>,
+    approved_by: Option<Principal>,
+    burned_at: Option<u64>,
+    burned_by: Option<Principal>,
+}
+
+#[derive(CandidType)]
+struct TxEvent {
+    time: u64,
+    caller: Principal,
+    operation: String,
+    details: Vec<(String, GenericValue)>,
+}
+
+#[derive(CandidType, Deserialize)]
+enum SupportedInterface {
+    Approval,
+    Mint,
+    Burn,
+    TransactionHistory,
+}
+
+fn non_empty(s: &str) -> Option<String> {
+    if s.is_empty() {
+        None
+    } else {
+        Some(s.to_string())
+    }
+}
+
+// v2 setters return nothing, so the only way to refuse is to trap
+fn check_role(state: &State, role: Role) {
+    if !state.has_role(&api::caller(), role) {
+        api::trap("Unauthorized");
+    }
+}
+
+fn token_metadata(state: &State, token_id: u64) -> Result<TokenMetadata> {
+    let nft = state.nft(token_id)?;
+    let history = state.history(token_id);
+    // (time, caller) of the logged event, if there is one
+    let event = |txid: Option<u128>| {
+        let tx = state.transaction(txid?)?;
+        Some((tx.timestamp, tx.caller))
+    };
+    let minted = event(history.minted);
+    let transferred = event(history.transferred);
+    let approved = event(history.approved);
+    let burned = event(history.burned);
+    Ok(TokenMetadata {
+        token_identifier: token_id as u128,
+        owner: Some(nft.owner).filter(|_| !nft.burned),
+        operator: nft.approved,
+        is_burned: nft.burned,
+        properties: properties(&nft.metadata()),
+        // tokens from before the transaction log have no mint record
+        minted_at: minted.map_or(0, |e| e.0),
+        minted_by: minted.map_or(MGMT, |e| e.1),
+        transferred_at: transferred.map(|e| e.0),
+        transferred_by: transferred.map(|e| e.1),
+        approved_at: approved.map(|e| e.0),
+        approved_by: approved.map(|e| e.1),
+        burned_at: burned.map(|e| e.0),
+        burned_by: burned.map(|e| e.1),
+    })
+}
+
+fn tx_event(tx: TxResult) -> TxEvent {
+    use GenericValue::{NatContent, Principal as P};
+    let token = |token_id: u64| ("token_identifier".to_string(), NatContent(token_id as u128));
+    let (operation, details) = match tx.transaction_type {
+        TransactionType::Transfer { token_id, from, to } => (
+            "transfer",
+            vec![
+                ("owner".to_string(), P(from)),
+                ("to".to_string(), P(to)),
+                token(token_id),
+            ],
+        ),
+        TransactionType::TransferFrom { token_id, from, to } => (
+            "transferFrom",
+            vec![
+                ("owner".to_string(), P(from)),
+                ("to".to_string(), P(to)),
+                token(token_id),
+            ],
+        ),
+        TransactionType::Approve { token_id, from, to } => (
+            "approve",
+            vec![
+                ("owner".to_string(), P(from)),
+                ("operator".to_string(), P(to)),
+                token(token_id),
+            ],
+        ),
+        TransactionType::Revoke { token_id, from, to } => (
+            "revoke",
+            vec![
+                ("owner".to_string(), P(from)),
+                ("operator".to_string(), P(to)),
+                token(token_id),
+            ],
+        ),
+        TransactionType::SetApprovalForAll { from, to } => (
+            "setApprovalForAll",
+            vec![
+                ("owner".to_string(), P(from)),
+                ("operator".to_string(), P(to)),
+            ],
+        ),
+        TransactionType::Mint { token_id, to } => {
+            ("mint", vec![("to".to_string(), P(to)), token(token_id)])
+        }
+        TransactionType::Burn { token_id, from } => (
+            "burn",
+            vec![("owner".to_string(), P(from)), token(token_id)],
+        ),
+    };
+    TxEvent {
+        time: tx.timestamp,
+        caller: tx.caller,
+        operation: operation.to_string(),
+        details,
+    }
+}
+
+// --------------------
+// collection interface
+// --------------------
+
+#[query(name = "metadata")]
+fn metadata() -> Metadata {
+    STATE.with(|state| {
+        let state = state.borrow();
+        Metadata {
+            logo: Some(state.logo_data_uri()),
+            name: non_empty(&state.name),
+            symbol: non_empty(&state.symbol),
+            created_at: state.created_at.unwrap_or(0),
+            upgraded_at: state.upgraded_at.unwrap_or(0),
+            custodians: state.custodians.iter().copied().collect(),
+        }
+    })
+}
+
+#[query(name = "stats")]
+fn stats() -> Stats {
+    Stats {
+        cycles: cycles(),
+        total_transactions: crate::total_transactions(),
+        total_unique_holders: total_unique_holders(),
+        total_supply: total_supply(),
+    }
+}
+
+#[query(name = "logo")]
+fn logo() -> Option<String> {
+    STATE.with(|state| Some(state.borrow().logo_data_uri()))
+}
+
+#[query(name = "name")]
+fn name() -> Option<String> {
+    STATE.with(|state| non_empty(&state.borrow().name))
+}
+
+#[query(name = "symbol")]
+fn symbol() -> Option<String> {
+    STATE.with(|state| non_empty(&state.borrow().symbol))
+}
+
+#[query(name = "custodians")]
+fn custodians() -> Vec<Principal> {
+    STATE.with(|state| state.borrow().custodians.iter().copied().collect())
+}
+
+#[query(name = "cycles")]
+fn cycles() -> u128 {
+    api::canister_balance() as u128
+}
+
+#[query(name = "totalUniqueHolders")]
+fn total_unique_holders() -> u128 {
+    STATE.with(|state| state.borrow().holder_count() as u128)
+}
+
+#[query(name = "totalSupply")]
+fn total_supply() -> u128 {
+    crate::total_supply() as u128
+}
+
+#[query(name = "supportedInterfaces")]
+fn supported_interfaces() -> &'static [SupportedInterface] {
+    &[
+        SupportedInterface::Approval,
+        SupportedInterface::Mint,
+        SupportedInterface::Burn,
+        SupportedInterface::TransactionHistory,
+    ]
+}
+
+#[update(name = "setName")]
+fn set_name(name: String) {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        check_role(&state, Role::MetadataEditor);
+        state.name = name;
+        crate::http::update_collection(&state);
+    })
+}
+
+#[update(name = "setSymbol")]
+fn set_symbol(symbol: String) {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        check_role(&state, Role::MetadataEditor);
+        state.symbol = symbol;
+        crate::http::update_collection(&state);
+    })
+}
+
+// takes the same data: URI that `logo` returns
+#[update(name = "setLogo")]
+fn set_logo(logo: String) {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        check_role(&state, Role::MetadataEditor);
+        let parsed = logo
+            .strip_prefix("data:")
+            .and_then(|logo| logo.split_once(";base64,"));
+        match parsed {
+            Some((logo_type, data)) => {
+                state.logo = Some(crate::LogoResult {
+                    logo_type: logo_type.to_string().into(),
+                    data: data.to_string().into(),
+                })
+            }
+            None => api::trap("The logo must be a base64 data: URI"),
+        }
+        crate::http::update_collection(&state);
+    })
+}
+
+#[update(name = "setCustodians")]
+fn set_custodians(custodians: Vec<Principal>) {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if !state.acts_alone(&api::caller()) {
+            api::trap("Unauthorized");
+        }
+        let custodians: HashSet<Principal> = custodians.into_iter().collect();
+        let removed: Vec<Principal> = state.custodians.difference(&custodians).copied().collect();
+        // granted before the others are revoked, so that only an empty list can leave no admin
+        let changes = custodians
+            .iter()
+            .map(|&user| (user, true))
+            .chain(removed.into_iter().map(|user| (user, false)));
+        for (user, granted) in changes {
+            if state.set_role(user, Role::Admin, granted).is_err() {
+                api::trap("There must be at least one custodian");
+            }
+        }
+    })
+}
+
+// ---------------
+// token interface
+// ---------------
+
+#[query(name = "tokenMetadata")]
+fn get_token_metadata(token_identifier: u128) -> Result<TokenMetadata> {
+    let token_id = u64::try_from(token_identifier)?;
+    STATE.with(|state| token_metadata(&state.borrow(), token_id))
+}
+
+#[query(name = "balanceOf")]
+fn balance_of(owner: Principal) -> Result {
+    match crate::balance_of(owner) {
+        0 => Err(NftError::OwnerNotFound),
+        balance => Ok(balance as u128),
+    }
+}
+
+// burned tokens have no owner
+#[query(name = "ownerOf")]
+fn owner_of(token_identifier: u128) -> Result<Option<Principal>> {
+    let token_id = u64::try_from(token_identifier)?;
+    let nft = STATE.with(|state| state.borrow().nft(token_id))?;
+    Ok(Some(nft.owner).filter(|_| !nft.burned))
+}
+
+#[query(name = "ownerTokenIdentifiers")]
+fn owner_token_identifiers(owner: Principal) -> Result<Vec<u128>> {
+    let tokens = STATE.with(|state| state.borrow().tokens_of(&owner));
+    if tokens.is_empty() {
+        Err(NftError::OwnerNotFound)
+    } else {
+        Ok(tokens.into_iter().map(u128::from).collect())
+    }
+}
+
+#[query(name = "ownerTokenMetadata")]
+fn owner_token_metadata(owner: Principal) -> Result<Vec<TokenMetadata>> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let tokens = state.tokens_of(&owner);
+        if tokens.is_empty() {
+            return Err(NftError::OwnerNotFound);
+        }
+        tokens
+            .into_iter()
+            .map(|token_id| token_metadata(&state, token_id))
+            .collect()
+    })
+}
+
+#[query(name = "operatorOf")]
+fn operator_of(token_identifier: u128) -> Result<Option<Principal>> {
+    let token_id = u64::try_from(token_identifier)?;
+    STATE.with(|state| Ok(state.borrow().nft(token_id)?.approved))
+}
+
+#[query(name = "operatorTokenIdentifiers")]
+fn operator_token_identifiers(operator: Principal) -> Result<Vec<u128>> {
+    let tokens = STATE.with(|state| state.borrow().approved_tokens(&operator));
+    if tokens.is_empty() {
+        Err(NftError::OperatorNotFound)
+    } else {
+        Ok(tokens.into_iter().map(u128::from).collect())
+    }
+}
+
+#[query(name = "operatorTokenMetadata")]
+fn operator_token_metadata(operator: Principal) -> Result<Vec<TokenMetadata>> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let tokens = state.approved_tokens(&operator);
+        if tokens.is_empty() {
+            return Err(NftError::OperatorNotFound);
+        }
+        tokens
+            .into_iter()
+            .map(|token_id| token_metadata(&state, token_id))
+            .collect()
+    })
+}
+
+// ------------------
+// approval interface
+// ------------------
+
+#[update(name = "approve")]
+fn approve(operator: Principal, token_identifier: u128) -> Result {
+    if operator == api::caller() {
+        return Err(NftError::SelfApprove);
+    }
+    Ok(crate::approve(operator, u64::try_from(token_identifier)?)?)
+}
+
+#[update(name = "setApprovalForAll")]
+fn set_approval_for_all(operator: Principal, is_approved: bool) -> Result {
+    if operator == api::caller() {
+        return Err(NftError::SelfApprove);
+    }
+    Ok(crate::set_approval_for_all(operator, is_approved)?)
+}
+
+#[query(name = "isApprovedForAll")]
+fn is_approved_for_all(owner: Principal, operator: Principal) -> Result<bool> {
+    Ok(STATE.with(|state| state.borrow().is_operator(&owner, &operator)))
+}
+
+// ------------------
+// transfer interface
+// ------------------
+
+#[update(name = "transfer")]
+fn transfer(to: Principal, token_identifier: u128) -> Result {
+    let caller = api::caller();
+    if to == caller {
+        return Err(NftError::SelfTransfer);
+    }
+    Ok(crate::transfer_from(
+        caller,
+        to,
+        u64::try_from(token_identifier)?,
+    )?)
+}
+
+#[update(name = "transferFrom")]
+fn transfer_from(from: Principal, to: Principal, token_identifier: u128) -> Result {
+    if to == from {
+        return Err(NftError::SelfTransfer);
+    }
+    let token_id = u64::try_from(token_identifier)?;
+    // v1 reports a wrong `from` as Other, which v2 has a proper error for
+    if crate::owner_of(token_id)? != from {
+        return Err(NftError::UnauthorizedOwner);
+    }
+    Ok(crate::transfer_from(from, to, token_id)?)
+}
+
+// --------------------------------
+// mint, burn and history interface
+// --------------------------------
+
+// Token identifiers are assigned in order, so `token_identifier` must be the next one.
+// The properties become the key-value data of a single rendered metadata part.
+#[update(name = "mint")]
+fn mint(to: Principal, token_identifier: u128, properties: Vec<(String, GenericValue)>) -> Result {
+    let next = STATE.with(|state| state.borrow().nft_count()) as u128;
+    if token_identifier < next {
+        return Err(NftError::ExistedNFT);
+    } else if token_identifier > next {
+        return Err(NftError::Other(format!(
+            "The next token identifier is {}",
+            next
+        )));
+    }
+    let mut key_val_data = HashMap::new();
+    for (key, val) in properties {
+        match MetadataVal::try_from(val) {
+            Ok(val) => {
+                key_val_data.insert(key, val);
+            }
+            Err(_) => {
+                return Err(NftError::Other(format!(
+                    "Unsupported value type for property {}",
+                    key
+                )))
+            }
+        }
+    }
+    let metadata = vec![MetadataPart {
+        purpose: MetadataPurpose::Rendered,
+        key_val_data,
+        data: vec![],
+    }];
+    match crate::mint(to, metadata, vec![]) {
+        Ok(res) => Ok(res.id),
+        Err(crate::ConstrainedError::Unauthorized) => Err(NftError::UnauthorizedOperator),
+        Err(crate::ConstrainedError::QuotaExceeded) => {
+            Err(NftError::Other("QuotaExceeded".to_string()))
+        }
+        Err(crate::ConstrainedError::Paused) => Err(NftError::Other("Paused".to_string())),
+        Err(crate::ConstrainedError::InvalidUpload) => {
+            Err(NftError::Other("InvalidUpload".to_string()))
+        }
+    }
+}
+
+#[update(name = "burn")]
+fn burn(token_identifier: u128) -> Result {
+    Ok(crate::burn(u64::try_from(token_identifier)?)?)
+}
+
+#[query(name = "transaction")]
+fn transaction(txid: u128) -> Result<TxEvent> {
+    let tx = crate::get_transaction(txid)?;
+    Ok(tx_event(tx))
+}
@@ -10,6 +10,18 @@ dfx identity new bob || true
 YOU=$(dfx identity get-principal)
 ALICE=$(dfx --identity alice identity get-principal)
 BOB=$(dfx --identity bob identity get-principal)
+# Runs a call, and stops the demo unless its output contains the expected text.
+expect() {
+    local expected=$1
+    shift
+    local output
+    output=$("$@")
+    echo "$output"
+    if [[ "$output" != *"$expected"* ]]; then
+        echo "(!) Expected the output to contain: $expected" >&2
+        exit 1
+    fi
+}
 echo '(*) Creating NFT with metadata "hello":'
 dfx canister call dip721-nft-container mintDip721 \
     "(principal\"$YOU\",vec{record{
@@ -46,6 +58,12 @@ echo '(*) Number of NFTs Alice owns:'
 dfx canister call dip721-nft-container balanceOfDip721 "(principal\"$ALICE\")"
 echo '(*) Alice approves Bob to transfer NFT 0 for her:'
 dfx --identity alice canister call dip721-nft-container approveDip721 "(principal\"$BOB\",0:nat64)"
+echo "(*) Principal approved for NFT 0 (Bob is $BOB):"
+expect "opt principal \"$BOB\"" dfx canister call dip721-nft-container getApprovedDip721 '(0:nat64)'
+echo "(*) Being approved doesn't let Bob approve someone else (Unauthorized):"
+expect 'Unauthorized' dfx --identity bob canister call dip721-nft-container approveDip721 "(principal\"$YOU\",0:nat64)"
+echo '(*) Bob is still the one approved:'
+expect "opt principal \"$BOB\"" dfx canister call dip721-nft-container getApprovedDip721 '(0:nat64)'
 echo '(*) Bob transfers NFT 0 to himself:'
 dfx --identity bob canister call dip721-nft-container transferFromDip721 "(principal\"$ALICE\",principal\"$BOB\",0:nat64)"
 echo "(*) Owner of NFT 0 (Bob is $BOB):"
@@ -56,3 +74,52 @@ echo '(*) Alice transfers 0 to herself:'
 dfx --identity alice canister call dip721-nft-container transferFromDip721 "(principal\"$BOB\",principal\"$ALICE\",0:nat64)"
 echo '(*) You are a custodian, so you can transfer the NFT back to yourself without approval:'
 dfx canister call dip721-nft-container transferFromDip721 "(principal\"$ALICE\",principal\"$YOU\",0:nat64)"
+echo '(*) No one is approved for NFT 0, since approvals are cleared when a token changes hands:'
+expect 'Ok = null' dfx canister call dip721-nft-container getApprovedDip721 '(0:nat64)'
+echo "(*) Alice is Bob's operator, not yours, so she can't approve anyone for your NFT (Unauthorized):"
+expect 'Unauthorized' dfx --identity alice canister call dip721-nft-container approveDip721 "(principal\"$BOB\",0:nat64)"
+echo '(*) Is Alice an operator for Bob? (true)'
+dfx canister call dip721-nft-container isApprovedForAll "(principal\"$BOB\",principal\"$ALICE\")"
+echo '(*) Is Alice an operator for you? (false)'
+dfx canister call dip721-nft-container isApprovedForAll "(principal\"$YOU\",principal\"$ALICE\")"
+echo '(*) You approve Alice to operate on any of your NFTs:'
+dfx canister call dip721-nft-container setApprovalForAllDip721 "(principal\"$ALICE\",true)"
+echo '(*) As your operator, Alice approves Bob to transfer NFT 0:'
+dfx --identity alice canister call dip721-nft-container approveDip721 "(principal\"$BOB\",0:nat64)"
+echo "(*) Principal approved for NFT 0 (Bob is $BOB):"
+expect "opt principal \"$BOB\"" dfx canister call dip721-nft-container getApprovedDip721 '(0:nat64)'
+echo '(*) Bob transfers NFT 0 to himself:'
+dfx --identity bob canister call dip721-nft-container transferFromDip721 "(principal\"$YOU\",principal\"$BOB\",0:nat64)"
+echo '(*) No one is approved for NFT 0 again:'
+expect 'Ok = null' dfx canister call dip721-nft-container getApprovedDip721 '(0:nat64)'
+echo '(*) NFT 1 does not exist (InvalidTokenId):'
+expect 'InvalidTokenId' dfx canister call dip721-nft-container getApprovedDip721 '(1:nat64)'
+echo '(*) You make Alice a custodian:'
+dfx canister call dip721-nft-container set_custodian "(principal\"$ALICE\",true)"
+echo '(*) You propose that two custodians approve sensitive actions; the threshold is still 1, so it is executed right away:'
+dfx canister call dip721-nft-container propose '(variant{SetThreshold=2:nat64},null)'
+echo '(*) You can no longer change the whitelist by yourself (Unauthorized):'
+dfx canister call dip721-nft-container add_to_white_list "(vec{record{principal=principal\"$BOB\";max_mint=null}})"
+echo '(*) You propose adding Bob to the whitelist instead, which stays open with only your approval:'
+dfx canister call dip721-nft-container propose "(variant{AddToWhiteList=vec{record{principal=principal\"$BOB\";max_mint=null}}},null)"
+echo '(*) Alice approves it, which executes it:'
+dfx --identity alice canister call dip721-nft-container approve_proposal '(1:nat64)'
+echo '(*) Is Bob white listed? (true)'
+dfx canister call dip721-nft-container is_white_listed "(principal\"$BOB\")"
+echo '(*) NFT 0 as an OpenSea-style JSON document:'
+curl -s "http://localhost:8000/0/metadata.json?canisterId=$(dfx canister id dip721-nft-container)"
+echo
+echo '(*) The collection as a JSON document, with its mint window and limit:'
+curl -s "http://localhost:8000/collection.json?canisterId=$(dfx canister id dip721-nft-container)"
+echo
+echo '(*) Every response, including the 404 for unknown paths, verifies against the certified data; the local gateway answers 500 for one that does not:'
+for path in / /logo /collection.json /index.html /0 /0/0 /0/metadata.json /0/ /0/7 /nope; do
+    code=$(curl -s -o /dev/null -w '%{http_code}' "http://localhost:8000$path?canisterId=$(dfx canister id dip721-nft-container)")
+    echo "$path: $code"
+    if [[ $code == 5* ]]; then
+        echo "(!) The response for $path did not verify" >&2
+        exit 1
+    fi
+done
+echo '(*) Bob is neither a custodian nor a minter, so cannot mint with mintDip721 (Unauthorized):'
+expect 'Unauthorized' dfx --identity bob canister call dip721-nft-container mintDip721 "(principal\"$BOB\",vec{},blob\"\")"
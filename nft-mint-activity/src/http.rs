@@ -1,19 +1,21 @@
 use std::borrow::Cow;
+use std::convert::TryFrom;
 use std::iter::FromIterator;
 use std::{cell::RefCell, collections::HashMap};
 
-use candid::CandidType;
+use candid::{CandidType, Principal};
+use chrono::{TimeZone, Utc};
 use ic_cdk::{
     api::{self, call},
     export::candid,
 };
-use ic_certified_map::{AsHashTree, Hash, RbTree};
+use ic_certified_map::{AsHashTree, Hash, HashTree, RbTree};
 use percent_encoding::percent_decode_str;
 use serde::{Deserialize, Serialize};
 use serde_cbor::Serializer;
 use sha2::{Digest, Sha256};
 
-use crate::{MetadataPurpose, MetadataVal, STATE};
+use crate::{LogoResult, MetadataPart, MetadataPurpose, MetadataVal, State, DEFAULT_LOGO, STATE};
 
 #[derive(CandidType, Deserialize)]
 struct HttpRequest {
@@ -30,6 +32,155 @@ struct HttpResponse<'a> {
     body: Cow<'a, [u8]>,
 }
 
+const NO_METADATA: &[u8] = b"No metadata for this NFT";
+const BURNED: &[u8] = b"This NFT has been burned";
+// Gateways check the response for a path that isn't in the tree against the hash at /index.html,
+// so every such path serves the same response, which is certified there.
+const NOT_FOUND: &[u8] = b"Not found";
+const NOT_FOUND_PATH: &str = "/index.html";
+
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+// The tests run outside of a canister, which has neither an id nor certified data.
+#[cfg(not(test))]
+fn canister_id() -> Principal {
+    api::id()
+}
+
+#[cfg(test)]
+fn canister_id() -> Principal {
+    Principal::from_slice(&[0xff])
+}
+
+#[cfg(not(test))]
+fn set_certified_data(data: &[u8]) {
+    api::set_certified_data(data);
+}
+
+#[cfg(test)]
+fn set_certified_data(_: &[u8]) {}
+
+// where the gateway serves `path` from this canister
+fn asset_url(path: &str) -> String {
+    if cfg!(mainnet) {
+        format!("https://{}.ic0.app{}", canister_id(), path)
+    } else {
+        format!("http://localhost:8000{}?canisterId={}", path, canister_id())
+    }
+}
+
+// the image itself; a logo that isn't valid base64 is served empty
+fn logo_bytes(logo: &LogoResult) -> Vec<u8> {
+    base64::decode(logo.data.as_bytes()).unwrap_or_default()
+}
+
+// default metadata: first non-preview metadata, or if there is none, first metadata
+fn default_part(metadata: &[MetadataPart]) -> Option<&MetadataPart> {
+    metadata
+        .iter()
+        .find(|x| x.purpose == MetadataPurpose::Rendered)
+        .or_else(|| metadata.get(0))
+}
+
+// An OpenSea-style description of the token, from the key-value data of its default part. The
+// keys other than the ones below become attributes, except for blobs, which have no JSON form.
+// The document has to come out the same every time for its hash to hold, so they are sorted.
+fn metadata_json(token_id: u64, owner: &Principal, metadata: &[MetadataPart]) -> String {
+    let empty = HashMap::new();
+    let data = default_part(metadata).map_or(&empty, |part| &part.key_val_data);
+    let text = |key: &str| match data.get(key) {
+        Some(MetadataVal::TextContent(text)) => Some(json_string(text)),
+        _ => None,
+    };
+    let mut fields = vec![];
+    if let Some(name) = text("name") {
+        fields.push(format!("\"name\":{}", name));
+    }
+    if let Some(description) = text("description") {
+        fields.push(format!("\"description\":{}", description));
+    }
+    let image =
+        text("location").unwrap_or_else(|| json_string(&asset_url(&format!("/{}", token_id))));
+    fields.push(format!("\"image\":{}", image));
+    if let Some(content_type) = text("contentType") {
+        fields.push(format!("\"content_type\":{}", content_type));
+    }
+    fields.push(format!("\"owner\":{}", json_string(&owner.to_text())));
+    fields.push(format!("\"token_id\":{}", token_id));
+    let mut keys: Vec<_> = data
+        .keys()
+        .filter(|key| {
+            ![
+                "name",
+                "description",
+                "location",
+                "locationType",
+                "contentType",
+            ]
+            .contains(&key.as_str())
+        })
+        .collect();
+    keys.sort();
+    let attributes: Vec<_> = keys
+        .into_iter()
+        .filter_map(|key| {
+            let value = match &data[key] {
+                MetadataVal::TextContent(text) => json_string(text),
+                MetadataVal::BlobContent(_) => return None,
+                MetadataVal::NatContent(n) => n.to_string(),
+                MetadataVal::Nat8Content(n) => n.to_string(),
+                MetadataVal::Nat16Content(n) => n.to_string(),
+                MetadataVal::Nat32Content(n) => n.to_string(),
+                MetadataVal::Nat64Content(n) => n.to_string(),
+            };
+            Some(format!(
+                "{{\"trait_type\":{},\"value\":{}}}",
+                json_string(key),
+                value
+            ))
+        })
+        .collect();
+    fields.push(format!("\"attributes\":[{}]", attributes.join(",")));
+    format!("{{{}}}", fields.join(","))
+}
+
+// Past 2262, which chrono can't represent in nanoseconds, the nanoseconds are given as they are.
+fn rfc3339(nanos: u64) -> String {
+    match i64::try_from(nanos) {
+        Ok(nanos) => Utc.timestamp_nanos(nanos).to_rfc3339(),
+        Err(_) => nanos.to_string(),
+    }
+}
+
+fn collection_json(state: &State) -> String {
+    format!(
+        concat!(
+            "{{\"name\":{},\"symbol\":{},\"image\":{},\"total_supply\":{},",
+            "\"begin_date\":{},\"end_date\":{},\"total_limit\":{}}}"
+        ),
+        json_string(&state.name),
+        json_string(&state.symbol),
+        json_string(&asset_url("/logo")),
+        state.supply(),
+        json_string(&rfc3339(state.begin_date)),
+        json_string(&rfc3339(state.end_date)),
+        state.total_limit
+    )
+}
+
 // This could reply with a lot of data. To return this data from the function would require it to be cloned,
 // because the thread_local! closure prevents us from returning data borrowed from inside it.
 // Luckily, it doesn't actually get returned from the exported WASM function, that's just an abstraction. 
@@ -46,50 +197,97 @@ fn http_request(/* req: HttpRequest */) /* -> HttpResponse */
     STATE.with(|state| {
         let state = state.borrow();
         let url = req.url.split('?').next().unwrap_or("/");
+        let mut response = respond(&state, url);
         let cert = format!(
             "certificate=:{}:, tree=:{}:",
             base64::encode(api::data_certificate().unwrap()),
             witness(&url)
-        )
-        .into();
-        let mut path = url[1..].split('/')
-            .map(|segment| percent_decode_str(segment).decode_utf8().unwrap());
-        let mut headers = HashMap::from_iter([
-            (
-                "Content-Security-Policy",
-                "default-src 'self' ; script-src 'none' ; frame-src 'none' ; object-src 'none'"
-                    .into(),
-            ),
-            ("IC-Certificate", cert),
-        ]);
+        );
+        let headers = &mut response.headers;
+        headers.insert(
+            "Content-Security-Policy",
+            "default-src 'self' ; script-src 'none' ; frame-src 'none' ; object-src 'none'".into(),
+        );
+        headers.insert("IC-Certificate", cert.into());
         if cfg!(mainnet) {
             headers.insert(
                 "Strict-Transport-Security",
                 "max-age=31536000; includeSubDomains".into(),
             );
         }
-        let root = path.next().unwrap_or_else(|| "".into());
-        let body;
-        let mut code = 200;
-        if root == "" {
-            body = format!("Total NFTs: {}", state.nfts.len())
-                .into_bytes()
-                .into();
-        } else {
-            if let Ok(num) = root.parse::<usize>() {
-                // /:something
-                if let Some(nft) = state.nfts.get(num) {
-                    // /:nft
-                    let img = path.next().unwrap_or_else(|| "".into());
-                    if img == "" {
-                        // /:nft/
-                        let part = nft
-                            .metadata
-                            .iter()
-                            .find(|x| x.purpose == MetadataPurpose::Rendered)
-                            .or_else(|| nft.metadata.get(0));
-                        if let Some(part) = part {
-                            // default metadata: first non-preview metadata, or if there is none, first metadata
+        call::reply((response,));
+    });
+}
+
+// The response for `url`, but for the headers that are the same for every path.
+fn respond<'a>(state: &'a State, url: &str) -> HttpResponse<'a> {
+    let mut headers = HashMap::new();
+    if HASHES.with(|hashes| hashes.borrow().get(url.as_bytes()).is_none()) {
+        // including other spellings of real paths, like with a trailing slash
+        return HttpResponse {
+            status_code: 404,
+            headers,
+            body: NOT_FOUND.into(),
+        };
+    }
+    let mut path = url[1..]
+        .split('/')
+        .map(|segment| percent_decode_str(segment).decode_utf8().unwrap());
+    let root = path.next().unwrap_or_else(|| "".into());
+    let body;
+    let mut code = 200;
+    if root == "" {
+        body = format!("Total NFTs: {}", state.supply())
+            .into_bytes()
+            .into();
+    } else if root == "logo" {
+        // /logo
+        let logo = state.logo.as_ref().unwrap_or(&DEFAULT_LOGO);
+        headers.insert("Content-Type", logo.logo_type.clone());
+        body = logo_bytes(logo).into();
+    } else if root == "collection.json" {
+        // /collection.json
+        headers.insert("Content-Type", "application/json".into());
+        body = collection_json(state).into_bytes().into();
+    } else if root == "index.html" {
+        // /index.html
+        code = 404;
+        body = NOT_FOUND.into();
+    } else {
+        if let Ok(num) = root.parse::<usize>() {
+            // /:something
+            if state.nfts.get(num).map_or(false, |nft| nft.is_burned()) {
+                // /:nft and everything under it, certified as such once burned
+                code = 410;
+                body = BURNED.into();
+            } else if let Some(nft) = state.nfts.get(num) {
+                // /:nft
+                let img = path.next().unwrap_or_else(|| "".into());
+                if img == "" {
+                    // /:nft/
+                    if let Some(part) = default_part(&nft.metadata) {
+                        body = part.data.as_slice().into();
+                        if let Some(MetadataVal::TextContent(mime)) =
+                            part.key_val_data.get("contentType")
+                        {
+                            headers.insert("Content-Type", mime.as_str().into());
+                        }
+                    } else {
+                        // no metadata to be found
+                        body = NO_METADATA.into();
+                    }
+                } else if img == "metadata.json" {
+                    // /:nft/metadata.json
+                    headers.insert("Content-Type", "application/json".into());
+                    body = metadata_json(num as u64, &nft.owner, &nft.metadata)
+                        .into_bytes()
+                        .into();
+                } else {
+                    // /:nft/:something
+                    if let Ok(num) = img.parse::<usize>() {
+                        // /:nft/:number
+                        if let Some(part) = nft.metadata.get(num) {
+                            // /:nft/:id
                             body = part.data.as_slice().into();
                             if let Some(MetadataVal::TextContent(mime)) =
                                 part.key_val_data.get("contentType")
@@ -97,45 +295,28 @@ fn http_request(/* req: HttpRequest */) /* -> HttpResponse */
                                 headers.insert("Content-Type", mime.as_str().into());
                             }
                         } else {
-                            // no metadata to be found
-                            body = b"No metadata for this NFT"[..].into();
+                            code = 404;
+                            body = NOT_FOUND.into();
                         }
                     } else {
-                        // /:nft/:something
-                        if let Ok(num) = img.parse::<usize>() {
-                            // /:nft/:number
-                            if let Some(part) = nft.metadata.get(num) {
-                                // /:nft/:id
-                                body = part.data.as_slice().into();
-                                if let Some(MetadataVal::TextContent(mime)) =
-                                    part.key_val_data.get("contentType")
-                                {
-                                    headers.insert("Content-Type", mime.as_str().into());
-                                }
-                            } else {
-                                code = 404;
-                                body = b"No such metadata part"[..].into();
-                            }
-                        } else {
-                            code = 400;
-                            body = format!("Invalid metadata ID {}", img).into_bytes().into();
-                        }
+                        code = 404;
+                        body = NOT_FOUND.into();
                     }
-                } else {
-                    code = 404;
-                    body = b"No such NFT"[..].into();
                 }
             } else {
-                code = 400;
-                body = format!("Invalid NFT ID {}", root).into_bytes().into();
+                code = 404;
+                body = NOT_FOUND.into();
             }
+        } else {
+            code = 404;
+            body = NOT_FOUND.into();
         }
-        call::reply((HttpResponse {
-            status_code: code,
-            headers,
-            body,
-        },));
-    });
+    }
+    HttpResponse {
+        status_code: code,
+        headers,
+        body,
+    }
 }
 
 thread_local! {
@@ -143,37 +324,117 @@ thread_local! {
     pub static HASHES: RefCell<RbTree<String, Hash>> = RefCell::new(RbTree::from_iter([("/".to_string(), *b"\x83\xd0\xf6\x70\x86\x5c\x36\x7c\xe9\x5f\x59\x59\x59\xab\xec\x46\xed\x7b\x64\x03\x3e\xce\xe9\xed\x77\x2e\x78\x79\x3f\x3b\xc1\x0f")]));
 }
 
+// after a token is minted or burned
 pub fn add_hash(tkid: u64) {
     crate::STATE.with(|state| {
         HASHES.with(|hashes| {
             let state = state.borrow();
             let mut hashes = hashes.borrow_mut();
             let nft = state.nfts.get(tkid as usize)?;
-            let mut default = false;
+            if nft.is_burned() {
+                // every path of a burned token serves the same 410 response
+                let hash: Hash = Sha256::digest(BURNED).into();
+                hashes.insert(format!("/{}", tkid), hash);
+                for i in 0..nft.metadata.len() {
+                    hashes.insert(format!("/{}/{}", tkid, i), hash);
+                }
+                hashes.insert(format!("/{}/metadata.json", tkid), hash);
+                insert_collection_hashes(&mut hashes, &state);
+                certify(&hashes);
+                return Some(());
+            }
+            let default = match default_part(&nft.metadata) {
+                Some(part) => Sha256::digest(&part.data),
+                None => Sha256::digest(NO_METADATA),
+            };
+            hashes.insert(format!("/{}", tkid), default.into());
             for (i, metadata) in nft.metadata.iter().enumerate() {
                 let hash = Sha256::digest(&metadata.data);
                 hashes.insert(format!("/{}/{}", tkid, i), hash.into());
-                if !default && matches!(metadata.purpose, MetadataPurpose::Rendered) {
-                    default = true;
-                    hashes.insert(format!("/{}", tkid), hash.into());
-                }
             }
-            hashes.insert(
-                "/".to_string(),
-                Sha256::digest(format!("Total NFTs: {}", state.nfts.len())).into(),
-            );
-            let cert = ic_certified_map::labeled_hash(b"http_assets", &hashes.root_hash());
-            api::set_certified_data(&cert);
+            insert_json_hash(&mut hashes, tkid, &nft.owner, &nft.metadata);
+            insert_collection_hashes(&mut hashes, &state);
+            certify(&hashes);
             Some(())
         })
     });
 }
 
+// Versions before this one only certified /<nft> for tokens with a rendered part, and none
+// certified /<nft>/metadata.json.
+pub fn rehash_uncertified(token_count: u64) {
+    let missing: Vec<_> = HASHES.with(|hashes| {
+        let hashes = hashes.borrow();
+        let uncertified = |path: String| hashes.get(path.as_bytes()).is_none();
+        (0..token_count)
+            .filter(|token_id| {
+                uncertified(format!("/{}", token_id))
+                    || uncertified(format!("/{}/metadata.json", token_id))
+            })
+            .collect()
+    });
+    for token_id in missing {
+        add_hash(token_id);
+    }
+}
+
+fn insert_json_hash(
+    hashes: &mut RbTree<String, Hash>,
+    token_id: u64,
+    owner: &Principal,
+    metadata: &[MetadataPart],
+) {
+    let json = metadata_json(token_id, owner, metadata);
+    hashes.insert(
+        format!("/{}/metadata.json", token_id),
+        Sha256::digest(json).into(),
+    );
+}
+
+// the paths that don't belong to a single token
+fn insert_collection_hashes(hashes: &mut RbTree<String, Hash>, state: &State) {
+    hashes.insert(
+        "/".to_string(),
+        Sha256::digest(format!("Total NFTs: {}", state.supply())).into(),
+    );
+    let logo = state.logo.as_ref().unwrap_or(&DEFAULT_LOGO);
+    hashes.insert("/logo".to_string(), Sha256::digest(logo_bytes(logo)).into());
+    let json = collection_json(state);
+    hashes.insert("/collection.json".to_string(), Sha256::digest(json).into());
+    hashes.insert(NOT_FOUND_PATH.to_string(), Sha256::digest(NOT_FOUND).into());
+}
+
+// after init, an upgrade, or a change to the collection's name, symbol or logo; takes the state,
+// since the callers have it borrowed already
+pub(crate) fn update_collection(state: &State) {
+    HASHES.with(|hashes| {
+        let mut hashes = hashes.borrow_mut();
+        insert_collection_hashes(&mut hashes, state);
+        certify(&hashes);
+    });
+}
+
+// after a token changes hands, since its metadata.json names the owner; takes the state, as the
+// caller is in the middle of changing it
+pub(crate) fn update_owner(state: &State, token_id: u64) {
+    HASHES.with(|hashes| {
+        let mut hashes = hashes.borrow_mut();
+        if let Some(nft) = state.nfts.get(token_id as usize) {
+            insert_json_hash(&mut hashes, token_id, &nft.owner, &nft.metadata);
+            certify(&hashes);
+        }
+    });
+}
+
+fn certify(hashes: &RbTree<String, Hash>) {
+    let cert = ic_certified_map::labeled_hash(b"http_assets", &hashes.root_hash());
+    set_certified_data(&cert);
+}
+
 fn witness(name: &str) -> String {
     HASHES.with(|hashes| {
         let hashes = hashes.borrow();
-        let witness = hashes.witness(name.as_bytes());
-        let tree = ic_certified_map::labeled(b"http_assets", witness);
+        let tree = ic_certified_map::labeled(b"http_assets", path_witness(&hashes, name));
         let mut data = vec![];
         let mut serializer = Serializer::new(&mut data);
         serializer.self_describe().unwrap();
@@ -181,3 +442,199 @@ fn witness(name: &str) -> String {
         base64::encode(data)
     })
 }
+
+// For a path that isn't in the tree, this proves its absence along with the hash at
+// NOT_FOUND_PATH.
+fn path_witness<'a>(hashes: &'a RbTree<String, Hash>, name: &str) -> HashTree<'a> {
+    let witness = hashes.witness(name.as_bytes());
+    if hashes.get(name.as_bytes()).is_none() {
+        merge(witness, hashes.witness(NOT_FOUND_PATH.as_bytes()))
+    } else {
+        witness
+    }
+}
+
+// Combines two witnesses of the same tree, which only differ in which subtrees they prune.
+fn merge<'a>(a: HashTree<'a>, b: HashTree<'a>) -> HashTree<'a> {
+    match (a, b) {
+        (HashTree::Pruned(_), b) => b,
+        (a, HashTree::Pruned(_)) => a,
+        (HashTree::Fork(a), HashTree::Fork(b)) => {
+            let ((a_left, a_right), (b_left, b_right)) = (*a, *b);
+            HashTree::Fork(Box::new((merge(a_left, b_left), merge(a_right, b_right))))
+        }
+        (HashTree::Labeled(label, a), HashTree::Labeled(_, b)) => {
+            HashTree::Labeled(label, Box::new(merge(*a, *b)))
+        }
+        (a, _) => a,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Nft;
+
+    fn hashes() -> RbTree<String, Hash> {
+        let mut hashes = RbTree::new();
+        for path in ["/", "/0", "/0/0", "/1", "/logo", "/collection.json"] {
+            hashes.insert(path.to_string(), Sha256::digest(path).into());
+        }
+        hashes.insert(NOT_FOUND_PATH.to_string(), Sha256::digest(NOT_FOUND).into());
+        hashes
+    }
+
+    // the leaf at `path` in a witness, if it isn't pruned away
+    fn lookup<'a>(tree: &'a HashTree<'a>, path: &str) -> Option<&'a [u8]> {
+        match tree {
+            HashTree::Fork(fork) => lookup(&fork.0, path).or_else(|| lookup(&fork.1, path)),
+            HashTree::Labeled(label, subtree) if label[..] == *path.as_bytes() => {
+                match &**subtree {
+                    HashTree::Leaf(data) => Some(&data[..]),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    // what a gateway checks the certified data against
+    fn certified(tree: HashTree) -> Hash {
+        ic_certified_map::labeled(b"http_assets", tree).reconstruct()
+    }
+
+    #[test]
+    fn witness_of_a_path_reveals_its_hash() {
+        let hashes = hashes();
+        let tree = path_witness(&hashes, "/0/0");
+        assert_eq!(lookup(&tree, "/0/0"), Some(&Sha256::digest("/0/0")[..]));
+        assert_eq!(lookup(&tree, NOT_FOUND_PATH), None);
+        assert_eq!(
+            certified(tree),
+            ic_certified_map::labeled_hash(b"http_assets", &hashes.root_hash())
+        );
+    }
+
+    #[test]
+    fn absence_proof_reveals_the_not_found_hash() {
+        let hashes = hashes();
+        for path in ["/nope", "/0/", "/0/7", "/2", ""] {
+            let tree = path_witness(&hashes, path);
+            assert_eq!(lookup(&tree, path), None);
+            assert_eq!(
+                lookup(&tree, NOT_FOUND_PATH),
+                Some(&Sha256::digest(NOT_FOUND)[..])
+            );
+            assert_eq!(
+                certified(tree),
+                ic_certified_map::labeled_hash(b"http_assets", &hashes.root_hash())
+            );
+        }
+    }
+
+    #[test]
+    fn merge_keeps_both_witnesses() {
+        let hashes = hashes();
+        let tree = merge(hashes.witness(b"/1"), hashes.witness(b"/logo"));
+        assert_eq!(lookup(&tree, "/1"), Some(&Sha256::digest("/1")[..]));
+        assert_eq!(lookup(&tree, "/logo"), Some(&Sha256::digest("/logo")[..]));
+        assert_eq!(tree.reconstruct(), hashes.root_hash());
+    }
+
+    // Token 0 has a rendered part and a preview, token 1 no metadata, and token 2 is burned.
+    fn collection() {
+        let nft = |id, metadata| Nft {
+            owner: Principal::from_slice(&[1]),
+            approved: None,
+            id,
+            metadata,
+            content: vec![],
+            history: None,
+            burned: Some(false),
+        };
+        let parts = || {
+            vec![
+                MetadataPart {
+                    purpose: MetadataPurpose::Preview,
+                    key_val_data: HashMap::new(),
+                    data: b"preview".to_vec(),
+                },
+                MetadataPart {
+                    purpose: MetadataPurpose::Rendered,
+                    key_val_data: HashMap::from_iter([(
+                        "contentType".to_string(),
+                        MetadataVal::TextContent("text/plain".to_string()),
+                    )]),
+                    data: b"rendered".to_vec(),
+                },
+            ]
+        };
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            state.name = "Test".to_string();
+            state.nfts = vec![nft(0, parts()), nft(1, vec![]), nft(2, parts())];
+            state.rebuild_indexes();
+        });
+        for token_id in 0..3 {
+            add_hash(token_id);
+        }
+        STATE.with(|state| state.borrow_mut().burn(2));
+        add_hash(2);
+    }
+
+    #[test]
+    fn served_bodies_match_their_certified_hashes() {
+        collection();
+        let paths = [
+            ("/", 200),
+            ("/logo", 200),
+            ("/collection.json", 200),
+            ("/0", 200),
+            ("/0/0", 200),
+            ("/0/1", 200),
+            ("/0/metadata.json", 200),
+            ("/1", 200),
+            ("/1/metadata.json", 200),
+            ("/2", 410),
+            ("/2/1", 410),
+            ("/2/metadata.json", 410),
+            ("/index.html", 404),
+            ("/0/2", 404),
+            ("/1/0", 404),
+            ("/3", 404),
+            ("/nope", 404),
+        ];
+        for &(url, code) in &paths {
+            STATE.with(|state| {
+                let state = state.borrow();
+                let response = respond(&state, url);
+                assert_eq!(response.status_code, code, "{}", url);
+                HASHES.with(|hashes| {
+                    let hashes = hashes.borrow();
+                    let tree = path_witness(&hashes, url);
+                    let hash = lookup(&tree, url).or_else(|| lookup(&tree, NOT_FOUND_PATH));
+                    assert_eq!(hash, Some(&Sha256::digest(&response.body)[..]), "{}", url);
+                    assert_eq!(
+                        certified(tree),
+                        ic_certified_map::labeled_hash(b"http_assets", &hashes.root_hash())
+                    );
+                });
+            });
+        }
+    }
+
+    #[test]
+    fn dates_past_2262_are_given_in_nanoseconds() {
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00+00:00");
+        assert_eq!(rfc3339(i64::MAX as u64 + 1), "9223372036854775808");
+        assert_eq!(rfc3339(u64::MAX), u64::MAX.to_string());
+    }
+
+    #[test]
+    fn merge_with_itself_is_the_same_witness() {
+        let hashes = hashes();
+        let tree = merge(hashes.witness(b"/0"), hashes.witness(b"/0"));
+        assert_eq!(lookup(&tree, "/0"), Some(&Sha256::digest("/0")[..]));
+        assert_eq!(tree.reconstruct(), hashes.root_hash());
+    }
+}
@@ -7,7 +7,7 @@ extern crate serde;
 
 use std::borrow::Cow;
 use std::cell::RefCell;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::convert::TryFrom;
 use std::iter::FromIterator;
 use std::mem;
@@ -15,7 +15,7 @@ use std::num::TryFromIntError;
 use std::result::Result as StdResult;
 
 use candid::{CandidType, Encode, Principal};
-use chrono::{Utc, TimeZone};
+use chrono::{DateTime, TimeZone, Utc};
 use ic_cdk::{
     api::{self, call},
     export::candid,
@@ -28,7 +28,8 @@ use sha2::{Digest, Sha256};
 use uriparse::URI;
 
 mod http;
-
+mod proposals;
+mod v2;
 
 const MGMT: Principal = Principal::from_slice(&[]);
 
@@ -52,24 +53,166 @@ fn pre_upgrade() {
 }
 #[post_upgrade]
 fn post_upgrade() {
-    let (StableState { state, hashes },) = storage::stable_restore().unwrap();
+    let StableState { mut state, hashes } = match storage::stable_restore() {
+        Ok((stable_state,)) => stable_state,
+        Err(e) => migrate_legacy_state().unwrap_or_else(|legacy| {
+            panic!(
+                "cannot decode the saved state ({}), nor as the legacy layout ({})",
+                e, legacy
+            )
+        }),
+    };
+    let legacy_burns = state.mark_legacy_burns();
+    // the indexes are derived from `nfts`, so this also covers versions that didn't have them
+    state.rebuild_indexes();
+    state.upgraded_at = Some(api::time());
     STATE.with(|state0| *state0.borrow_mut() = state);
     let hashes = hashes.into_iter().collect();
     http::HASHES.with(|hashes0| *hashes0.borrow_mut() = hashes);
+    for token_id in legacy_burns {
+        http::add_hash(token_id);
+    }
+    http::rehash_uncertified(STATE.with(|state| state.borrow().nfts.len() as u64));
+    // versions before /logo, /collection.json and /index.html have no hashes for them
+    STATE.with(|state| http::update_collection(&state.borrow()));
 }
 
+// The first version saved its State with these types, some of which later versions changed in
+// place; everything added since is an Option, so that it decodes from the versions before it.
 #[derive(CandidType, Deserialize)]
-struct InitArgs {
-    custodians: Option<HashSet<Principal>>,
+struct LegacyStableState {
+    state: LegacyState,
+    hashes: Vec<(String, Hash)>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LegacyState {
+    nfts: Vec<LegacyNft>,
+    custodians: HashSet<Principal>,
+    operators: HashMap<Principal, HashSet<Principal>>,
     logo: Option<LogoResult>,
     name: String,
     symbol: String,
+    txid: u128,
     white_list: Vec<Principal>,
-    begin_date: String,
+    begin_date: String, // "%Y-%m-%d %H:%M:%S", in UTC
     end_date: String,
     total_limit: String,
 }
 
+#[derive(CandidType, Deserialize)]
+struct LegacyNft {
+    owner: Principal,
+    approved: Option<Principal>,
+    id: u64,
+    metadata: MetadataDesc,
+    content: Vec<u8>,
+}
+
+fn migrate_legacy_state() -> StdResult<StableState, String> {
+    let (stable_state,): (LegacyStableState,) = storage::stable_restore()?;
+    let (legacy, hashes) = (stable_state.state, stable_state.hashes);
+    let date = |text: &str| {
+        let date = Utc
+            .datetime_from_str(text, "%Y-%m-%d %H:%M:%S")
+            .map_err(|e| format!("invalid date {:?}: {}", text, e))?;
+        u64::try_from(date.timestamp_nanos())
+            .map_err(|_| format!("date {:?} is before the epoch", text))
+    };
+    let begin_date = date(&legacy.begin_date)?;
+    let end_date = date(&legacy.end_date)?;
+    let state = State {
+        nfts: legacy
+            .nfts
+            .into_iter()
+            .map(|nft| Nft {
+                owner: nft.owner,
+                approved: nft.approved,
+                id: nft.id,
+                metadata: nft.metadata,
+                content: nft.content,
+                history: None,
+                burned: None,
+            })
+            .collect(),
+        custodians: legacy.custodians,
+        operators: legacy.operators,
+        owners: None,
+        approvals: None,
+        // so that post_upgrade marks the tokens burned before burns were tracked
+        burned: None,
+        logo: legacy.logo,
+        name: legacy.name,
+        symbol: legacy.symbol,
+        // those transactions were never recorded, but their txids stay taken
+        tx_base: Some(legacy.txid),
+        txs: None,
+        white_list: legacy
+            .white_list
+            .into_iter()
+            .map(|principal| (principal, None))
+            .collect(),
+        default_mint_limit: None,
+        minted: None,
+        // the only schedule there was: the whitelist, for the whole window
+        phases: Some(build_phases(vec![], begin_date, end_date, vec![])?),
+        begin_date,
+        end_date,
+        // it was never enforced, so an unparsable limit is no limit
+        total_limit: legacy.total_limit.trim().parse().unwrap_or(u64::MAX),
+        created_at: None,
+        upgraded_at: None,
+        proposals: None,
+        roles: None,
+        purge_burned: None,
+    };
+    Ok(StableState { state, hashes })
+}
+
+#[derive(CandidType, Deserialize)]
+struct InitArgs {
+    custodians: Option<HashSet<Principal>>,
+    logo: Option<LogoResult>,
+    name: String,
+    symbol: String,
+    white_list: Vec<WhiteListEntry>,
+    default_mint_limit: Option<u64>,
+    begin_date: MintTime,
+    end_date: MintTime,
+    total_limit: String,
+    phases: Option<Vec<MintPhaseArgs>>,
+    proposal_threshold: Option<u64>,
+    purge_burned: Option<bool>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct WhiteListEntry {
+    principal: Principal,
+    // overrides default_mint_limit for this principal
+    max_mint: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+enum MintTime {
+    Rfc3339(String),
+    Nanos(u64),
+}
+
+impl MintTime {
+    // nanoseconds since the epoch, comparable with api::time()
+    fn to_nanos(&self) -> StdResult<u64, String> {
+        match self {
+            MintTime::Nanos(nanos) => Ok(*nanos),
+            MintTime::Rfc3339(text) => {
+                let date = DateTime::parse_from_rfc3339(text.trim())
+                    .map_err(|e| format!("invalid RFC 3339 date {:?}: {}", text, e))?;
+                u64::try_from(date.timestamp_nanos())
+                    .map_err(|_| format!("date {:?} is before the epoch", text))
+            }
+        }
+    }
+}
+
 #[init]
 fn init(args: InitArgs) {
     STATE.with(|state| {
@@ -80,36 +223,50 @@ fn init(args: InitArgs) {
         state.name = args.name;
         state.symbol = args.symbol;
         state.logo = args.logo;
-        state.white_list = args.white_list;
-        state.total_limit = args.total_limit;
-        let fmt = "%Y-%m-%d %H:%M:%S";
-        
-        match Utc.datetime_from_str(&args.begin_date, fmt){
-            Ok(_) => {
-                state.begin_date = args.begin_date
-            },
-            Err(e) => {
-                panic!("{}",e)
-            },
-        };
-        
-        match Utc.datetime_from_str(&args.end_date, fmt){
-            Ok(_) => {
-                state.end_date = args.end_date
-            },
-            Err(e) => {
-                panic!("{}",e)
-            },
+        state.created_at = Some(api::time());
+        state.burned = Some(0);
+        state.white_list = args
+            .white_list
+            .into_iter()
+            .map(|entry| (entry.principal, entry.max_mint))
+            .collect();
+        state.default_mint_limit = args.default_mint_limit;
+        state.purge_burned = args.purge_burned;
+        state.total_limit = match args.total_limit.trim().parse::<u64>() {
+            Ok(limit) if limit > 0 => limit,
+            Ok(_) => panic!("total_limit must be greater than zero"),
+            Err(e) => panic!("invalid total_limit {:?}: {}", args.total_limit, e),
         };
-
+        state.begin_date = args
+            .begin_date
+            .to_nanos()
+            .unwrap_or_else(|e| panic!("begin_date: {}", e));
+        state.end_date = args
+            .end_date
+            .to_nanos()
+            .unwrap_or_else(|e| panic!("end_date: {}", e));
+        if state.begin_date > state.end_date {
+            panic!("begin_date must not be after end_date");
+        }
+        let (begin_date, end_date) = (state.begin_date, state.end_date);
+        let phases = args.phases.unwrap_or_default();
+        let phases =
+            build_phases(phases, begin_date, end_date, vec![]).unwrap_or_else(|e| panic!("{}", e));
+        state.phases = Some(phases);
+        http::update_collection(&state);
+        state.proposals = Some(proposals::Proposals::new(args.proposal_threshold.unwrap_or(1)));
     });
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Clone)]
 enum Error {
     Unauthorized,
     InvalidTokenId,
     ZeroAddress,
+    InvalidTxId,
+    LastAdmin,
+    InvalidProposalId,
+    InvalidPhases,
     Other,
 }
 
@@ -130,52 +287,46 @@ fn balance_of(user: Principal) -> u64 {
     STATE.with(|state| {
         state
             .borrow()
-            .nfts
-            .iter()
-            .filter(|n| n.owner == user)
-            .count() as u64
+            .owned(&user)
+            .map(|tokens| tokens.len() as u64)
+            .unwrap_or(0)
     })
 }
 
 #[query(name = "ownerOfDip721")]
 fn owner_of(token_id: u64) -> Result<Principal> {
-    STATE.with(|state| {
-        let owner = state
-            .borrow()
-            .nfts
-            .get(usize::try_from(token_id)?)
-            .ok_or(Error::InvalidTokenId)?
-            .owner;
-        Ok(owner)
-    })
+    STATE.with(|state| Ok(state.borrow().live_nft(token_id)?.owner))
 }
 
 #[update(name = "transferFromDip721")]
 fn transfer_from(from: Principal, to: Principal, token_id: u64) -> Result {
+    // a token sent to the burn address would be lost without being counted as burned
+    if to == MGMT {
+        return Err(Error::ZeroAddress);
+    }
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        let state = &mut *state;
-        let nft = state
-            .nfts
-            .get_mut(usize::try_from(token_id)?)
-            .ok_or(Error::InvalidTokenId)?;
+        let nft = state.live_nft(token_id)?;
         let caller = api::caller();
         if nft.owner != caller
             && nft.approved != Some(caller)
-            && !state
-                .operators
-                .get(&from)
-                .map(|s| s.contains(&caller))
-                .unwrap_or(false)
-            && !state.custodians.contains(&caller)
+            && !state.is_operator(&nft.owner, &caller)
+            && !state.acts_alone(&caller)
         {
             Err(Error::Unauthorized)
         } else if nft.owner != from {
             Err(Error::Other)
         } else {
-            nft.approved = None;
-            nft.owner = to;
-            Ok(state.next_txid())
+            state.nfts[token_id as usize].owner = to;
+            state.set_approved(token_id, None);
+            state.move_token(token_id, Some(from), to);
+            http::update_owner(&state, token_id);
+            let transaction_type = if caller == from {
+                TransactionType::Transfer { token_id, from, to }
+            } else {
+                TransactionType::TransferFrom { token_id, from, to }
+            };
+            Ok(state.record_tx(transaction_type))
         }
     })
 }
@@ -196,7 +347,8 @@ fn supported_interfaces() -> &'static [InterfaceId] {
         InterfaceId::Approval, // Psychedelic/DIP721#5
         InterfaceId::Burn,
         InterfaceId::Mint,
-    ]    
+        InterfaceId::TransactionHistory,
+    ]
 }
 
 #[derive(CandidType, Deserialize, Clone)]
@@ -227,9 +379,10 @@ const DEFAULT_LOGO: LogoResult = LogoResult {
     logo_type: Cow::Borrowed("image/png"),
 };
 
+// burned tokens don't count
 #[query(name = "totalSupplyDip721")]
 fn total_supply() -> u64 {
-    STATE.with(|state| state.borrow().nfts.len() as u64)
+    STATE.with(|state| state.borrow().supply())
 }
 
 #[export_name = "canister_query getMetadataDip721"]
@@ -239,11 +392,7 @@ fn get_metadata(/* token_id: u64 */) /* -> Result<&'static MetadataDesc> */
     let token_id = call::arg_data::<(u64,)>().0;
     let res: Result<()> = STATE.with(|state| {
         let state = state.borrow();
-        let metadata = &state
-            .nfts
-            .get(usize::try_from(token_id)?)
-            .ok_or(Error::InvalidTokenId)?
-            .metadata;
+        let metadata = &state.live_nft(token_id)?.metadata;
         call::reply((Ok::<_, Error>(metadata),));
         Ok(())
     });
@@ -266,19 +415,107 @@ fn get_metadata_for_user(/* user: Principal */) /* -> Vec<ExtendedMetadataResult
     STATE.with(|state| {
         let state = state.borrow();
         let metadata: Vec<_> = state
-            .nfts
-            .iter()
-            .filter(|n| n.owner == user)
-            .map(|n| ExtendedMetadataResult {
-
-                metadata_desc: &n.metadata,
-                token_id: n.id,
+            .owned(&user)
+            .into_iter()
+            .flatten()
+            .map(|&id| ExtendedMetadataResult {
+                metadata_desc: &state.nfts[id as usize].metadata,
+                token_id: id,
             })
             .collect();
         call::reply((metadata,));
     });
 }
 
+#[query(name = "getTokenIdsForUserDip721")]
+fn get_token_ids_for_user(user: Principal) -> Vec<u64> {
+    STATE.with(|state| state.borrow().tokens_of(&user))
+}
+
+const MAX_PAGE_SIZE: u64 = 100;
+const MAX_TOKENS_PER_QUERY: u64 = 1000;
+
+#[derive(CandidType)]
+struct MetadataPartRef<'a> {
+    purpose: &'a MetadataPurpose,
+    key_val_data: &'a HashMap<String, MetadataVal>,
+    data: &'a [u8],
+}
+
+#[derive(CandidType)]
+struct ExtendedMetadataPage<'a> {
+    items: Vec<ExtendedMetadataPartsResult<'a>>,
+    // pass as `cursor` to fetch the next page; absent on the last page
+    next: Option<u64>,
+}
+
+#[derive(CandidType)]
+struct ExtendedMetadataPartsResult<'a> {
+    metadata_desc: Vec<MetadataPartRef<'a>>,
+    token_id: u64,
+}
+
+#[export_name = "canister_query getMetadataForUserPageDip721"]
+fn get_metadata_for_user_page(
+    /* user: Principal, cursor: Option<u64>, limit: u64, include_data: bool */
+) /* -> ExtendedMetadataPage */
+{
+    ic_cdk::setup();
+    let (user, cursor, limit, include_data) =
+        call::arg_data::<(Principal, Option<u64>, u64, bool)>();
+    let limit = limit.min(MAX_PAGE_SIZE) as usize;
+    STATE.with(|state| {
+        let state = state.borrow();
+        let mut tokens = state
+            .owned(&user)
+            .into_iter()
+            .flat_map(|ids| ids.range(cursor.unwrap_or(0)..));
+        let items: Vec<_> = tokens
+            .by_ref()
+            .take(limit)
+            .map(|&id| ExtendedMetadataPartsResult {
+                metadata_desc: state.nfts[id as usize]
+                    .metadata
+                    .iter()
+                    .map(|part| MetadataPartRef {
+                        purpose: &part.purpose,
+                        key_val_data: &part.key_val_data,
+                        data: if include_data { part.data.as_slice() } else { &[] },
+                    })
+                    .collect(),
+                token_id: id,
+            })
+            .collect();
+        let next = tokens.next().copied();
+        call::reply((ExtendedMetadataPage { items, next },));
+    });
+}
+
+#[derive(CandidType)]
+struct TokenOwner {
+    token_id: u64,
+    owner: Principal,
+}
+
+#[query(name = "listTokens")]
+fn list_tokens(start: u64, limit: u64) -> Vec<TokenOwner> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let start = usize::try_from(start).unwrap_or(usize::MAX);
+        state
+            .nfts
+            .iter()
+            .skip(start)
+            .take(limit.min(MAX_TOKENS_PER_QUERY) as usize)
+            .filter(|nft| !nft.is_burned())
+            .map(|nft| TokenOwner {
+                token_id: nft.id,
+                owner: nft.owner,
+            })
+            .collect()
+    })
+}
+
 // ----------------------
 // notification interface
 // ----------------------
@@ -321,25 +558,21 @@ fn safe_transfer_from_notify(
 fn approve(user: Principal, token_id: u64) -> Result {
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        let state = &mut *state;
         let caller = api::caller();
-        let nft = state
-            .nfts
-            .get_mut(usize::try_from(token_id)?)
-            .ok_or(Error::InvalidTokenId)?;
+        let nft = state.live_nft(token_id)?;
         if nft.owner != caller
-            && nft.approved != Some(caller)
-            && !state
-                .operators
-                .get(&user)
-                .map(|s| s.contains(&caller))
-                .unwrap_or(false)
-            && !state.custodians.contains(&caller)
+            && !state.is_operator(&nft.owner, &caller)
+            && !state.acts_alone(&caller)
         {
             Err(Error::Unauthorized)
         } else {
-            nft.approved = Some(user);
-            Ok(state.next_txid())
+            let from = nft.owner;
+            state.set_approved(token_id, Some(user));
+            Ok(state.record_tx(TransactionType::Approve {
+                token_id,
+                from,
+                to: user,
+            }))
         }
     })
 }
@@ -349,78 +582,129 @@ fn set_approval_for_all(operator: Principal, is_approved: bool) -> Result {
     STATE.with(|state| {
         let mut state = state.borrow_mut();
         let caller = api::caller();
-        if operator != caller {
-            let operators = state.operators.entry(caller).or_default();
-            if operator == MGMT {
-                if !is_approved {
-                    operators.clear();
-                } else {
-                    // cannot enable everyone as an operator
-                }
-            } else {
-                if is_approved {
-                    operators.insert(operator);
-                } else {
-                    operators.remove(&operator);
-                }
-            }
+        let operators = state.operators.entry(caller).or_default();
+        let changed = if operator == caller {
+            false
+        } else if operator == MGMT {
+            // cannot enable everyone as an operator, but disabling it removes them all
+            !is_approved && !mem::take(operators).is_empty()
+        } else if is_approved {
+            operators.insert(operator)
+        } else {
+            operators.remove(&operator)
+        };
+        // only changes are logged, so that every txid handed out refers to one
+        if !changed {
+            return Err(Error::Other);
         }
-        Ok(state.next_txid())
+        Ok(state.record_tx(TransactionType::SetApprovalForAll {
+            from: caller,
+            to: operator,
+        }))
     })
 }
 
-// #[query(name = "getApprovedDip721")] // Psychedelic/DIP721#5
-fn _get_approved(token_id: u64) -> Result<Principal> {
-    STATE.with(|state| {
-        let approved = state
-            .borrow()
-            .nfts
-            .get(usize::try_from(token_id)?)
-            .ok_or(Error::InvalidTokenId)?
-            .approved
-            .unwrap_or_else(api::caller);
-        Ok(approved)
-    })
+// None if no one is approved, rather than an error, so that it can be told apart from a bad id.
+#[query(name = "getApprovedDip721")]
+fn get_approved(token_id: u64) -> Result<Option<Principal>> {
+    STATE.with(|state| Ok(state.borrow().nft(token_id)?.approved))
 }
 
+// with the caller as the owner; see isApprovedForAll for any owner
 #[query(name = "isApprovedForAllDip721")]
 fn is_approved_for_all(operator: Principal) -> bool {
+    STATE.with(|state| state.borrow().is_operator(&api::caller(), &operator))
+}
+
+// -----------------------------
+// transaction history interface
+// -----------------------------
+
+const MAX_TRANSACTIONS_PER_QUERY: u64 = 1000;
+
+#[query(name = "getTransaction")]
+fn get_transaction(txid: u128) -> Result<TxResult> {
     STATE.with(|state| {
         state
             .borrow()
-            .operators
-            .get(&api::caller())
-            .map(|s| s.contains(&operator))
-            .unwrap_or(false)
+            .transaction(txid)
+            .cloned()
+            .ok_or(Error::InvalidTxId)
+    })
+}
+
+#[query(name = "getTransactions")]
+fn get_transactions(start: u128, len: u64) -> Vec<TxResult> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let start = start.saturating_sub(state.tx_base.unwrap_or(0));
+        let start = usize::try_from(start).unwrap_or(usize::MAX);
+        let len = len.min(MAX_TRANSACTIONS_PER_QUERY) as usize;
+        state
+            .txs
+            .iter()
+            .flatten()
+            .skip(start)
+            .take(len)
+            .cloned()
+            .collect()
     })
 }
 
+#[query(name = "totalTransactions")]
+fn total_transactions() -> u128 {
+    STATE.with(|state| state.borrow().transaction_count())
+}
+
 // --------------
 // mint interface
 // --------------
 
+// Minters mint here bound only by total_limit; everyone else goes through simpleMintDip721 and
+// the mint schedule.
 #[update(name = "mintDip721")]
 fn mint(
     to: Principal,
     metadata: MetadataDesc,
     blob_content: Vec<u8>,
+) -> Result<MintResult, ConstrainedError> {
+    if !STATE.with(|state| state.borrow().has_role(&api::caller(), Role::Minter)) {
+        return Err(ConstrainedError::Unauthorized);
+    }
+    push_nft(to, metadata, blob_content)
+}
+
+// authorization and the schedule are up to the caller
+fn push_nft(
+    to: Principal,
+    metadata: MetadataDesc,
+    blob_content: Vec<u8>,
 ) -> Result<MintResult, ConstrainedError> {
     let (txid, tkid) = STATE.with(|state| {
         let mut state = state.borrow_mut();
-        // everyone can mint
-        // if !state.custodians.contains(&api::caller()) {
-        //     return Err(ConstrainedError::Unauthorized);
-        // }
         let new_id = state.nfts.len() as u64;
+        // checked in the same borrow as the push, so the cap holds no matter which endpoint mints
+        if new_id >= state.total_limit {
+            return Err(ConstrainedError::SoldOut);
+        }
         let nft = Nft {
             owner: to,
             approved: None,
             id: new_id,
             metadata,
             content: blob_content,
+            history: None,
+            burned: None,
         };
         state.nfts.push(nft);
-        Ok((state.next_txid(), new_id))
+        state.move_token(new_id, None, to);
+        Ok((
+            state.record_tx(TransactionType::Mint {
+                token_id: new_id,
+                to,
+            }),
+            new_id,
+        ))
     })?;
     http::add_hash(tkid);
     Ok(MintResult {
@@ -429,7 +713,6 @@ fn mint(
     })
 }
 
-
 #[update(name = "simpleMintDip721")]
 fn simple_mint(
     to: Principal,
@@ -438,10 +721,9 @@ fn simple_mint(
     name: String,
     origin: String,
 ) -> Result<MintResult, ConstrainedError> {
-
     let mut metadata: HashMap<String, MetadataVal> = HashMap::new();
-        use MetadataVal::*;
-    if uri.len() > 0  {
+    use MetadataVal::*;
+    if uri.len() > 0 {
         if let Err(_) = URI::try_from(&*uri) {
             return Err(ConstrainedError::Unauthorized);
         }
@@ -463,53 +745,277 @@ fn simple_mint(
         key_val_data: metadata,
     };
 
+    let phase = STATE.with(|state| state.borrow().check_mint(&to, api::time()))?;
+    let res = push_nft(to, vec![metadata], vec![])?;
     STATE.with(|state| {
-
-        let begin_date = &state.borrow().begin_date;
-        let end_date = &state.borrow().end_date;
-
-        let now = Utc::now().timestamp_millis();
-        let fmt = "%Y-%m-%d %H:%M:%S";
-        let begin_date_utc = Utc.datetime_from_str(begin_date, fmt).unwrap().timestamp_millis();
-        let end_date_utc = Utc.datetime_from_str(end_date, fmt).unwrap().timestamp_millis();
-        
-        if now < begin_date_utc || now > end_date_utc {
-            return Err(ConstrainedError::TimeError);
-        }
-
-        let white_list = &state.borrow().white_list;
-
-        if !white_list.contains(&to){
-            return Err(ConstrainedError::Unauthorized);
-        }
-        mint(to, vec![metadata], vec![])
-    })
-    
+        let mut state = state.borrow_mut();
+        *state
+            .minted
+            .get_or_insert_with(Default::default)
+            .entry(to)
+            .or_default() += 1;
+        let phase = &mut state.phases.get_or_insert_with(Vec::new)[phase];
+        phase.minted += 1;
+        *phase.minted_by.entry(to).or_default() += 1;
+    });
+    Ok(res)
 }
 
 #[query(name = "whiteList")]
-fn white_list() -> Result<Vec<Principal>> {
+fn white_list() -> Result<Vec<WhiteListEntry>> {
     STATE.with(|state| {
-        let white_list = state.borrow().white_list.clone();
+        let white_list: Vec<_> = state
+            .borrow()
+            .white_list
+            .iter()
+            .map(|(&principal, &max_mint)| WhiteListEntry {
+                principal,
+                max_mint,
+            })
+            .collect();
         ic_cdk::println!("white_list {:?}", white_list);
         Ok(white_list)
     })
 }
 
+// None means the principal may be minted to without limit
+#[query(name = "remainingMintAllowance")]
+fn remaining_mint_allowance(user: Principal) -> Option<u64> {
+    STATE.with(|state| state.borrow().remaining_mints(&user, api::time()))
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct MintDate {
+    begin_date: u64,
+    end_date: u64,
+}
+
 #[query(name = "nftMintDate")]
-fn nft_mint_date() -> String {
+fn nft_mint_date() -> MintDate {
     STATE.with(|state| {
-        let nft_mint_date = format!("{},{}",state.borrow().begin_date.clone(), state.borrow().end_date.clone());
+        let state = state.borrow();
+        let nft_mint_date = MintDate {
+            begin_date: state.begin_date,
+            end_date: state.end_date,
+        };
         ic_cdk::println!("nft_mint_date {:?}", nft_mint_date);
         nft_mint_date
     })
 }
 
+// -----------
+// mint phases
+// -----------
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum PhaseEligibility {
+    Everyone,
+    // the activity's white_list, including each entry's max_mint
+    WhiteList,
+    Principals(HashSet<Principal>),
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+struct MintPhaseArgs {
+    name: String,
+    begin_date: MintTime,
+    end_date: MintTime,
+    eligibility: PhaseEligibility,
+    per_wallet_limit: Option<u64>,
+    supply_cap: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct MintPhase {
+    name: String,
+    begin_date: u64,
+    end_date: u64,
+    eligibility: PhaseEligibility,
+    per_wallet_limit: Option<u64>,
+    supply_cap: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct PhaseState {
+    phase: MintPhase,
+    minted: u64,
+    minted_by: HashMap<Principal, u64>,
+}
+
+#[derive(CandidType)]
+struct PhaseInfo<'a> {
+    index: u64,
+    phase: &'a MintPhase,
+    minted: u64,
+}
+
+impl MintPhaseArgs {
+    fn into_phase(self) -> StdResult<MintPhase, String> {
+        let begin_date = self
+            .begin_date
+            .to_nanos()
+            .map_err(|e| format!("phase {:?} begin_date: {}", self.name, e))?;
+        let end_date = self
+            .end_date
+            .to_nanos()
+            .map_err(|e| format!("phase {:?} end_date: {}", self.name, e))?;
+        Ok(MintPhase {
+            name: self.name,
+            begin_date,
+            end_date,
+            eligibility: self.eligibility,
+            per_wallet_limit: self.per_wallet_limit,
+            supply_cap: self.supply_cap,
+        })
+    }
+}
+
+// Validates a schedule against the activity's window. Phases keep the counters of the
+// same-named phase in `old`, so editing a running schedule doesn't reset anyone's limits.
+// An empty schedule is a single phase covering the whole window, open to the whitelist.
+fn build_phases(
+    args: Vec<MintPhaseArgs>,
+    begin_date: u64,
+    end_date: u64,
+    mut old: Vec<PhaseState>,
+) -> StdResult<Vec<PhaseState>, String> {
+    let mut configs = args
+        .into_iter()
+        .map(MintPhaseArgs::into_phase)
+        .collect::<StdResult<Vec<_>, _>>()?;
+    if configs.is_empty() {
+        configs.push(MintPhase {
+            name: "default".to_string(),
+            begin_date,
+            end_date,
+            eligibility: PhaseEligibility::WhiteList,
+            per_wallet_limit: None,
+            supply_cap: None,
+        });
+    }
+    let mut phases: Vec<PhaseState> = Vec::with_capacity(configs.len());
+    for phase in configs {
+        if phase.begin_date > phase.end_date {
+            return Err(format!("phase {:?} ends before it begins", phase.name));
+        }
+        if phase.begin_date < begin_date || phase.end_date > end_date {
+            return Err(format!("phase {:?} is outside the mint window", phase.name));
+        }
+        if phases.iter().any(|p| p.phase.name == phase.name) {
+            return Err(format!("duplicate phase {:?}", phase.name));
+        }
+        let (minted, minted_by) = match old.iter().position(|p| p.phase.name == phase.name) {
+            Some(i) => {
+                let old = old.swap_remove(i);
+                (old.minted, old.minted_by)
+            }
+            None => (0, HashMap::new()),
+        };
+        phases.push(PhaseState {
+            phase,
+            minted,
+            minted_by,
+        });
+    }
+    Ok(phases)
+}
+
+#[export_name = "canister_query mintPhases"]
+fn mint_phases() /* -> Vec<PhaseInfo> */
+{
+    ic_cdk::setup();
+    STATE.with(|state| {
+        let state = state.borrow();
+        let phases: Vec<_> = state
+            .phases()
+            .iter()
+            .enumerate()
+            .map(|(i, p)| PhaseInfo {
+                index: i as u64,
+                phase: &p.phase,
+                minted: p.minted,
+            })
+            .collect();
+        call::reply((phases,));
+    });
+}
+
+#[export_name = "canister_query currentPhase"]
+fn current_phase() /* -> Option<PhaseInfo> */
+{
+    ic_cdk::setup();
+    STATE.with(|state| {
+        let state = state.borrow();
+        let phase = state.active_phase(api::time()).map(|i| PhaseInfo {
+            index: i as u64,
+            phase: &state.phases()[i].phase,
+            minted: state.phases()[i].minted,
+        });
+        call::reply((phase,));
+    });
+}
+
+#[derive(CandidType)]
+struct MintEligibility<'a> {
+    eligible: bool,
+    // why simpleMintDip721 would reject a mint to this principal right now
+    reason: Option<ConstrainedError>,
+    phase: Option<&'a str>,
+    // set when no phase is active and a later one is scheduled
+    opens_at: Option<u64>,
+    // set while a phase is active
+    closes_at: Option<u64>,
+}
+
+#[export_name = "canister_query canMint"]
+fn can_mint(/* user: Principal */) /* -> MintEligibility */
+{
+    ic_cdk::setup();
+    let user = call::arg_data::<(Principal,)>().0;
+    STATE.with(|state| {
+        let state = state.borrow();
+        let now = api::time();
+        let reason = state.check_mint(&user, now).err();
+        let active = state
+            .active_phase(now)
+            .filter(|_| state.begin_date <= now && now <= state.end_date);
+        let opens_at = if active.is_none() {
+            state
+                .phases()
+                .iter()
+                .map(|p| p.phase.begin_date.max(state.begin_date))
+                .filter(|&begin| begin > now && begin <= state.end_date)
+                .min()
+        } else {
+            None
+        };
+        let eligibility = MintEligibility {
+            eligible: reason.is_none(),
+            reason,
+            phase: active.map(|i| state.phases()[i].phase.name.as_str()),
+            opens_at,
+            closes_at: active.map(|i| state.phases()[i].phase.end_date.min(state.end_date)),
+        };
+        call::reply((eligibility,));
+    });
+}
+
+#[update]
+fn set_mint_phases(phases: Vec<MintPhaseArgs>) -> Result<()> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.acts_alone(&api::caller()) {
+            state.set_phases(phases)
+        } else {
+            Err(Error::Unauthorized)
+        }
+    })
+}
 
 #[query(name = "totalLimit")]
-fn total_limit() -> String {
+fn total_limit() -> u64 {
     STATE.with(|state| {
-        let total_limit = state.borrow().total_limit.clone();
+        let total_limit = state.borrow().total_limit;
         ic_cdk::println!("total_limit {:?}", total_limit);
         total_limit
     })
@@ -519,36 +1025,69 @@ fn total_limit() -> String {
 // burn interface
 // --------------
 
+// The owner, the approved principal and the owner's operators can burn a token.
 #[update(name = "burnDip721")]
 fn burn(token_id: u64) -> Result {
-    STATE.with(|state| {
+    let txid = STATE.with(|state| {
         let mut state = state.borrow_mut();
-        let nft = state
-            .nfts
-            .get_mut(usize::try_from(token_id)?)
-            .ok_or(Error::InvalidTokenId)?;
-        if nft.owner != api::caller() {
+        let nft = state.live_nft(token_id)?;
+        let (from, caller) = (nft.owner, api::caller());
+        if from != caller && nft.approved != Some(caller) && !state.is_operator(&from, &caller) {
             Err(Error::Unauthorized)
         } else {
-            nft.owner = MGMT;
-            Ok(state.next_txid())
+            state.burn(token_id);
+            Ok(state.record_tx(TransactionType::Burn { token_id, from }))
         }
-    })
+    })?;
+    http::add_hash(token_id);
+    Ok(txid)
 }
 
+// Saved whole on every upgrade. Add new fields as Options, so that the previous version's saved
+// state still decodes.
 #[derive(CandidType, Deserialize, Default)]
 struct State {
     nfts: Vec<Nft>,
     custodians: HashSet<Principal>,
     operators: HashMap<Principal, HashSet<Principal>>, // owner to operators
+    owners: Option<HashMap<Principal, BTreeSet<u64>>>, // owner to token ids, mirrors Nft::owner
+    approvals: Option<HashMap<Principal, BTreeSet<u64>>>, // approved principal to token ids, mirrors Nft::approved
+    burned: Option<u64>, // number of burned tokens; None until the burns from before it existed are counted
     logo: Option<LogoResult>,
     name: String,
     symbol: String,
-    txid: u128,
-    white_list: Vec<Principal>,
-    begin_date: String,
-    end_date: String,
-    total_limit: String,
+    tx_base: Option<u128>, // txids handed out before the transaction log, which are not in it
+    txs: Option<Vec<TxResult>>,
+    white_list: HashMap<Principal, Option<u64>>, // principal to its own mint limit
+    default_mint_limit: Option<u64>,
+    minted: Option<HashMap<Principal, u64>>, // by simpleMintDip721, per recipient
+    phases: Option<Vec<PhaseState>>,
+    begin_date: u64, // nanoseconds, IC time
+    end_date: u64,
+    total_limit: u64,
+    created_at: Option<u64>,
+    upgraded_at: Option<u64>,
+    proposals: Option<proposals::Proposals>,
+    roles: Option<HashMap<Role, HashSet<Principal>>>, // every role but Admin, whose members are `custodians`
+    purge_burned: Option<bool>, // whether burning a token also drops its content and metadata data
+}
+
+// Admins are the custodians, and have every other role as well.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+enum Role {
+    Admin,
+    Minter,
+    MetadataEditor,
+    WhiteListManager,
+}
+
+impl Role {
+    const ALL: [Role; 4] = [
+        Role::Admin,
+        Role::Minter,
+        Role::MetadataEditor,
+        Role::WhiteListManager,
+    ];
 }
 
 #[derive(CandidType, Deserialize)]
@@ -558,6 +1097,23 @@ struct Nft {
     id: u64,
     metadata: MetadataDesc,
     content: Vec<u8>,
+    history: Option<TokenHistory>,
+    burned: Option<bool>, // None in tokens from versions that didn't track burns
+}
+
+impl Nft {
+    fn is_burned(&self) -> bool {
+        self.burned == Some(true)
+    }
+}
+
+// The txids of the last mint, transfer, approval and burn of a token, for the DIP721 v2 token metadata.
+#[derive(CandidType, Deserialize, Clone, Copy, Default)]
+struct TokenHistory {
+    minted: Option<u128>,
+    transferred: Option<u128>,
+    approved: Option<u128>,
+    burned: Option<u128>,
 }
 
 type MetadataDesc = Vec<MetadataPart>;
@@ -594,12 +1150,396 @@ enum MetadataVal {
     Nat64Content(u64),
 }
 
+#[derive(CandidType, Deserialize, Clone)]
+struct TxResult {
+    fee: u128,
+    caller: Principal,
+    timestamp: u64,
+    transaction_type: TransactionType,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+enum TransactionType {
+    Transfer {
+        token_id: u64,
+        from: Principal,
+        to: Principal,
+    },
+    TransferFrom {
+        token_id: u64,
+        from: Principal,
+        to: Principal,
+    },
+    Approve {
+        token_id: u64,
+        from: Principal,
+        to: Principal,
+    },
+    SetApprovalForAll {
+        from: Principal,
+        to: Principal,
+    },
+    Mint {
+        token_id: u64,
+        to: Principal,
+    },
+    Burn {
+        token_id: u64,
+        from: Principal,
+    },
+}
+
+impl TransactionType {
+    fn token_id(&self) -> Option<u64> {
+        match *self {
+            TransactionType::Transfer { token_id, .. }
+            | TransactionType::TransferFrom { token_id, .. }
+            | TransactionType::Approve { token_id, .. }
+            | TransactionType::Mint { token_id, .. }
+            | TransactionType::Burn { token_id, .. } => Some(token_id),
+            TransactionType::SetApprovalForAll { .. } => None,
+        }
+    }
+}
+
 impl State {
-    fn next_txid(&mut self) -> u128 {
-        let txid = self.txid;
-        self.txid += 1;
+    // every state-changing call is logged, and its index in the log is the txid handed back to the caller
+    fn record_tx(&mut self, transaction_type: TransactionType) -> u128 {
+        let txid = self.transaction_count();
+        if let Some(nft) = transaction_type
+            .token_id()
+            .and_then(|token_id| self.nfts.get_mut(token_id as usize))
+        {
+            let history = nft.history.get_or_insert_with(Default::default);
+            let last = match transaction_type {
+                TransactionType::Mint { .. } => &mut history.minted,
+                TransactionType::Transfer { .. } | TransactionType::TransferFrom { .. } => {
+                    &mut history.transferred
+                }
+                TransactionType::Approve { .. } => &mut history.approved,
+                TransactionType::Burn { .. } => &mut history.burned,
+                TransactionType::SetApprovalForAll { .. } => unreachable!(),
+            };
+            *last = Some(txid);
+        }
+        self.txs.get_or_insert_with(Vec::new).push(TxResult {
+            fee: 0,
+            caller: api::caller(),
+            timestamp: api::time(),
+            transaction_type,
+        });
         txid
     }
+
+    fn nft(&self, token_id: u64) -> Result<&Nft> {
+        self.nfts
+            .get(usize::try_from(token_id)?)
+            .ok_or(Error::InvalidTokenId)
+    }
+
+    // a token that exists and hasn't been burned
+    fn live_nft(&self, token_id: u64) -> Result<&Nft> {
+        let nft = self.nft(token_id)?;
+        if nft.is_burned() {
+            Err(Error::InvalidTokenId)
+        } else {
+            Ok(nft)
+        }
+    }
+
+    // tokens that exist and haven't been burned
+    fn supply(&self) -> u64 {
+        self.nfts.len() as u64 - self.burned.unwrap_or(0)
+    }
+
+    // Burned tokens are dropped from the owner index rather than moved to the burn address.
+    // Authorization is up to the caller.
+    fn burn(&mut self, token_id: u64) {
+        self.set_approved(token_id, None);
+        let owner = mem::replace(&mut self.nfts[token_id as usize].owner, MGMT);
+        let nft = &mut self.nfts[token_id as usize];
+        nft.burned = Some(true);
+        if self.purge_burned == Some(true) {
+            for part in &mut nft.metadata {
+                part.data = vec![];
+            }
+            nft.content = vec![];
+        }
+        let owners = self.owners.get_or_insert_with(Default::default);
+        if let Some(tokens) = owners.get_mut(&owner) {
+            tokens.remove(&token_id);
+            if tokens.is_empty() {
+                owners.remove(&owner);
+            }
+        }
+        *self.burned.get_or_insert(0) += 1;
+    }
+
+    // Before burns were tracked, burning moved a token to the burn address. Those tokens are
+    // marked burned by the first upgrade that finds them uncounted, which returns their ids.
+    // The indexes are left to be rebuilt.
+    fn mark_legacy_burns(&mut self) -> Vec<u64> {
+        if self.burned.is_some() {
+            return vec![];
+        }
+        let mut tokens = vec![];
+        for nft in &mut self.nfts {
+            if nft.owner == MGMT {
+                nft.burned = Some(true);
+                tokens.push(nft.id);
+            }
+        }
+        self.burned = Some(tokens.len() as u64);
+        tokens
+    }
+
+    fn history(&self, token_id: u64) -> TokenHistory {
+        self.nft(token_id)
+            .ok()
+            .and_then(|nft| nft.history)
+            .unwrap_or_default()
+    }
+
+    fn transaction(&self, txid: u128) -> Option<&TxResult> {
+        let index = txid.checked_sub(self.tx_base.unwrap_or(0))?;
+        self.txs.as_ref()?.get(usize::try_from(index).ok()?)
+    }
+
+    fn transaction_count(&self) -> u128 {
+        self.tx_base.unwrap_or(0) + self.txs.as_ref().map_or(0, |txs| txs.len() as u128)
+    }
+
+    fn owned(&self, user: &Principal) -> Option<&BTreeSet<u64>> {
+        self.owners.as_ref()?.get(user)
+    }
+
+    // in ascending order
+    fn tokens_of(&self, user: &Principal) -> Vec<u64> {
+        self.owned(user)
+            .map(|tokens| tokens.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    // in ascending order
+    fn approved_tokens(&self, user: &Principal) -> Vec<u64> {
+        self.approvals
+            .as_ref()
+            .and_then(|approvals| approvals.get(user))
+            .map(|tokens| tokens.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    // owners other than the burn address
+    fn holder_count(&self) -> u64 {
+        self.owners
+            .iter()
+            .flat_map(|owners| owners.keys())
+            .filter(|&&owner| owner != MGMT)
+            .count() as u64
+    }
+
+    fn is_operator(&self, owner: &Principal, operator: &Principal) -> bool {
+        self.operators
+            .get(owner)
+            .map(|s| s.contains(operator))
+            .unwrap_or(false)
+    }
+
+    // sets Nft::approved and keeps `approvals` in step with it
+    fn set_approved(&mut self, token_id: u64, approved: Option<Principal>) {
+        let nft = &mut self.nfts[token_id as usize];
+        let old = mem::replace(&mut nft.approved, approved);
+        let approvals = self.approvals.get_or_insert_with(Default::default);
+        if let Some(old) = old {
+            if let Some(tokens) = approvals.get_mut(&old) {
+                tokens.remove(&token_id);
+                if tokens.is_empty() {
+                    approvals.remove(&old);
+                }
+            }
+        }
+        if let Some(new) = approved {
+            approvals.entry(new).or_default().insert(token_id);
+        }
+    }
+
+    fn proposal_threshold(&self) -> u64 {
+        self.proposals.as_ref().map_or(1, |proposals| proposals.threshold())
+    }
+
+    // a custodian who can use the powers that proposals are for without making one
+    fn acts_alone(&self, user: &Principal) -> bool {
+        self.custodians.contains(user) && self.proposal_threshold() == 1
+    }
+
+    fn has_role(&self, user: &Principal, role: Role) -> bool {
+        self.custodians.contains(user) || self.role_members(role).contains(user)
+    }
+
+    // The white list is behind proposals for custodians, but whitelist managers keep editing it
+    // directly at any threshold.
+    fn manages_white_list(&self, user: &Principal) -> bool {
+        self.acts_alone(user) || self.role_members(Role::WhiteListManager).contains(user)
+    }
+
+    fn role_members(&self, role: Role) -> HashSet<Principal> {
+        if role == Role::Admin {
+            return self.custodians.clone();
+        }
+        self.roles
+            .as_ref()
+            .and_then(|roles| roles.get(&role))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn set_role(&mut self, user: Principal, role: Role, granted: bool) -> Result<()> {
+        if role == Role::Admin {
+            if granted {
+                self.custodians.insert(user);
+            } else if self.custodians.len() == 1 && self.custodians.contains(&user) {
+                return Err(Error::LastAdmin);
+            } else {
+                self.custodians.remove(&user);
+            }
+        } else {
+            let members = self
+                .roles
+                .get_or_insert_with(HashMap::new)
+                .entry(role)
+                .or_default();
+            if granted {
+                members.insert(user);
+            } else {
+                members.remove(&user);
+            }
+        }
+        Ok(())
+    }
+
+    fn move_token(&mut self, token_id: u64, from: Option<Principal>, to: Principal) {
+        let owners = self.owners.get_or_insert_with(Default::default);
+        if let Some(from) = from {
+            if let Some(tokens) = owners.get_mut(&from) {
+                tokens.remove(&token_id);
+                if tokens.is_empty() {
+                    owners.remove(&from);
+                }
+            }
+        }
+        owners.entry(to).or_default().insert(token_id);
+    }
+
+    fn rebuild_indexes(&mut self) {
+        let mut owners: HashMap<Principal, BTreeSet<u64>> = HashMap::new();
+        let mut approvals: HashMap<Principal, BTreeSet<u64>> = HashMap::new();
+        for nft in self.nfts.iter().filter(|nft| !nft.is_burned()) {
+            owners.entry(nft.owner).or_default().insert(nft.id);
+            if let Some(approved) = nft.approved {
+                approvals.entry(approved).or_default().insert(nft.id);
+            }
+        }
+        self.owners = Some(owners);
+        self.approvals = Some(approvals);
+    }
+
+    fn phases(&self) -> &[PhaseState] {
+        self.phases.as_deref().unwrap_or(&[])
+    }
+
+    // Checked first without the old schedule, which build_phases consumes, so that a bad schedule
+    // leaves the current one and its counters in place.
+    fn set_phases(&mut self, args: Vec<MintPhaseArgs>) -> Result<()> {
+        build_phases(args.clone(), self.begin_date, self.end_date, vec![])
+            .map_err(|_| Error::InvalidPhases)?;
+        let old = self.phases.take().unwrap_or_default();
+        let phases = build_phases(args, self.begin_date, self.end_date, old);
+        self.phases = Some(phases.map_err(|_| Error::InvalidPhases)?);
+        Ok(())
+    }
+
+    // the first phase in schedule order whose window contains `now`
+    fn active_phase(&self, now: u64) -> Option<usize> {
+        self.phases()
+            .iter()
+            .position(|p| p.phase.begin_date <= now && now <= p.phase.end_date)
+    }
+
+    // everything simple_mint checks before minting to `to`; on success, the phase the mint counts against
+    fn check_mint(&self, to: &Principal, now: u64) -> StdResult<usize, ConstrainedError> {
+        if now < self.begin_date || now > self.end_date {
+            return Err(ConstrainedError::TimeError);
+        }
+        let index = self.active_phase(now).ok_or(ConstrainedError::TimeError)?;
+        let PhaseState {
+            phase,
+            minted,
+            minted_by,
+        } = &self.phases()[index];
+        match &phase.eligibility {
+            PhaseEligibility::Everyone => {}
+            PhaseEligibility::WhiteList => {
+                if !self.white_list.contains_key(to) {
+                    return Err(ConstrainedError::Unauthorized);
+                }
+                if self.white_list_allowance(to) == Some(0) {
+                    return Err(ConstrainedError::QuotaExceeded);
+                }
+            }
+            PhaseEligibility::Principals(principals) => {
+                if !principals.contains(to) {
+                    return Err(ConstrainedError::Unauthorized);
+                }
+            }
+        }
+        if let Some(limit) = phase.per_wallet_limit {
+            if minted_by.get(to).copied().unwrap_or(0) >= limit {
+                return Err(ConstrainedError::QuotaExceeded);
+            }
+        }
+        if phase.supply_cap.map_or(false, |cap| *minted >= cap)
+            || self.nfts.len() as u64 >= self.total_limit
+        {
+            return Err(ConstrainedError::SoldOut);
+        }
+        Ok(index)
+    }
+
+    // How many more times the active phase lets `to` be minted to before QuotaExceeded, or None
+    // if it doesn't limit `to`. Some(0) if no phase is active or the active one excludes `to`.
+    fn remaining_mints(&self, to: &Principal, now: u64) -> Option<u64> {
+        let index = match self.active_phase(now) {
+            Some(index) => index,
+            None => return Some(0),
+        };
+        let PhaseState {
+            phase, minted_by, ..
+        } = &self.phases()[index];
+        let white_list = match &phase.eligibility {
+            PhaseEligibility::Everyone => None,
+            PhaseEligibility::WhiteList if self.white_list.contains_key(to) => {
+                self.white_list_allowance(to)
+            }
+            PhaseEligibility::Principals(principals) if principals.contains(to) => None,
+            _ => return Some(0),
+        };
+        let per_wallet = phase
+            .per_wallet_limit
+            .map(|limit| limit.saturating_sub(minted_by.get(to).copied().unwrap_or(0)));
+        match (white_list, per_wallet) {
+            (Some(left), Some(per_wallet)) => Some(left.min(per_wallet)),
+            (left, per_wallet) => left.or(per_wallet),
+        }
+    }
+
+    // what is left of a whitelisted principal's max_mint, or of default_mint_limit without one
+    fn white_list_allowance(&self, to: &Principal) -> Option<u64> {
+        let max_mint = self.white_list.get(to).copied().flatten();
+        max_mint.or(self.default_mint_limit).map(|max| {
+            let minted = self.minted.as_ref().and_then(|minted| minted.get(to));
+            max.saturating_sub(minted.copied().unwrap_or(0))
+        })
+    }
 }
 
 #[derive(CandidType, Deserialize)]
@@ -615,6 +1555,8 @@ enum InterfaceId {
 enum ConstrainedError {
     Unauthorized,
     TimeError,
+    SoldOut,
+    QuotaExceeded,
     // InvalidUri,
 }
 
@@ -622,8 +1564,9 @@ enum ConstrainedError {
 fn set_name(name: String) -> Result<()> {
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        if state.custodians.contains(&api::caller()) {
+        if state.has_role(&api::caller(), Role::MetadataEditor) {
             state.name = name;
+            http::update_collection(&state);
             Ok(())
         } else {
             Err(Error::Unauthorized)
@@ -635,8 +1578,9 @@ fn set_name(name: String) -> Result<()> {
 fn set_symbol(sym: String) -> Result<()> {
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        if state.custodians.contains(&api::caller()) {
+        if state.has_role(&api::caller(), Role::MetadataEditor) {
             state.symbol = sym;
+            http::update_collection(&state);
             Ok(())
         } else {
             Err(Error::Unauthorized)
@@ -648,8 +1592,9 @@ fn set_symbol(sym: String) -> Result<()> {
 fn set_logo(logo: Option<LogoResult>) -> Result<()> {
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        if state.custodians.contains(&api::caller()) {
+        if state.has_role(&api::caller(), Role::MetadataEditor) {
             state.logo = logo;
+            http::update_collection(&state);
             Ok(())
         } else {
             Err(Error::Unauthorized)
@@ -661,13 +1606,8 @@ fn set_logo(logo: Option<LogoResult>) -> Result<()> {
 fn set_custodian(user: Principal, custodian: bool) -> Result<()> {
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        if state.custodians.contains(&api::caller()) {
-            if custodian {
-                state.custodians.insert(user);
-            } else {
-                state.custodians.remove(&user);
-            }
-            Ok(())
+        if state.acts_alone(&api::caller()) {
+            state.set_role(user, Role::Admin, custodian)
         } else {
             Err(Error::Unauthorized)
         }
@@ -678,3 +1618,119 @@ fn set_custodian(user: Principal, custodian: bool) -> Result<()> {
 fn is_custodian(principal: Principal) -> bool {
     STATE.with(|state| state.borrow().custodians.contains(&principal))
 }
+
+#[update]
+fn grant_role(user: Principal, role: Role) -> Result<()> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.acts_alone(&api::caller()) {
+            state.set_role(user, role, true)
+        } else {
+            Err(Error::Unauthorized)
+        }
+    })
+}
+
+#[update]
+fn revoke_role(user: Principal, role: Role) -> Result<()> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.acts_alone(&api::caller()) {
+            state.set_role(user, role, false)
+        } else {
+            Err(Error::Unauthorized)
+        }
+    })
+}
+
+// only the roles granted to the user; custodians also have every other role
+#[query]
+fn roles_of(user: Principal) -> Vec<Role> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        Role::ALL
+            .iter()
+            .copied()
+            .filter(|&role| state.role_members(role).contains(&user))
+            .collect()
+    })
+}
+
+#[query]
+fn role_members(role: Role) -> Vec<Principal> {
+    STATE.with(|state| state.borrow().role_members(role).into_iter().collect())
+}
+
+#[update]
+fn add_to_white_list(entries: Vec<WhiteListEntry>) -> Result<()> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.manages_white_list(&api::caller()) {
+            for entry in entries {
+                state.white_list.insert(entry.principal, entry.max_mint);
+            }
+            Ok(())
+        } else {
+            Err(Error::Unauthorized)
+        }
+    })
+}
+
+#[update]
+fn remove_from_white_list(users: Vec<Principal>) -> Result<()> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.manages_white_list(&api::caller()) {
+            for user in users {
+                state.white_list.remove(&user);
+            }
+            Ok(())
+        } else {
+            Err(Error::Unauthorized)
+        }
+    })
+}
+
+#[update]
+fn clear_white_list() -> Result<()> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.manages_white_list(&api::caller()) {
+            state.white_list.clear();
+            Ok(())
+        } else {
+            Err(Error::Unauthorized)
+        }
+    })
+}
+
+#[query]
+fn is_white_listed(principal: Principal) -> bool {
+    STATE.with(|state| state.borrow().white_list.contains_key(&principal))
+}
+
+#[update]
+fn set_purge_burned(purge: bool) -> Result<()> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.acts_alone(&api::caller()) {
+            state.purge_burned = Some(purge);
+            Ok(())
+        } else {
+            Err(Error::Unauthorized)
+        }
+    })
+}
+
+#[update]
+fn set_default_mint_limit(limit: Option<u64>) -> Result<()> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.manages_white_list(&api::caller()) {
+            state.default_mint_limit = limit;
+            Ok(())
+        } else {
+            Err(Error::Unauthorized)
+        }
+    })
+}
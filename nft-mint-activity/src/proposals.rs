@@ -0,0 +1,243 @@
+// k-of-n approval for the custodians' most sensitive powers. While the threshold is 1, every
+// custodian still has those powers directly, and a proposal is executed as soon as it is made.
+// From 2 on, the direct endpoints refuse custodians and proposals are the only way.
+
+use candid::{CandidType, Principal};
+use ic_cdk::{api, export::candid};
+
+use crate::{
+    Error, MintPhaseArgs, Result, Role, State, TransactionType, WhiteListEntry, MGMT, STATE,
+};
+
+const DEFAULT_LIFETIME: u64 = 7 * 24 * 60 * 60 * 1_000_000_000; // nanoseconds
+const MAX_PROPOSALS_PER_QUERY: u64 = 100;
+
+#[derive(CandidType, Deserialize, Clone)]
+enum ProposalAction {
+    SetCustodian {
+        user: Principal,
+        custodian: bool,
+    },
+    // SetRole with Admin is SetCustodian
+    SetRole {
+        user: Principal,
+        role: Role,
+        granted: bool,
+    },
+    // moves a token regardless of who owns it or has approved what
+    ForceTransfer {
+        token_id: u64,
+        to: Principal,
+    },
+    SetMintPhases(Vec<MintPhaseArgs>),
+    SetDefaultMintLimit(Option<u64>),
+    AddToWhiteList(Vec<WhiteListEntry>),
+    RemoveFromWhiteList(Vec<Principal>),
+    ClearWhiteList,
+    SetPurgeBurned(bool),
+    SetThreshold(u64),
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+enum ProposalStatus {
+    Open,
+    Executed,
+    Failed(Error),
+    Expired,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+struct Proposal {
+    id: u64,
+    proposer: Principal,
+    action: ProposalAction,
+    approvals: Vec<Principal>,
+    created_at: u64,
+    expires_at: u64,
+    status: ProposalStatus,
+}
+
+#[derive(CandidType, Deserialize, Default)]
+pub struct Proposals {
+    threshold: u64,
+    proposals: Vec<Proposal>, // by id
+}
+
+impl Proposals {
+    pub fn new(threshold: u64) -> Self {
+        Proposals {
+            threshold: threshold.max(1),
+            proposals: vec![],
+        }
+    }
+
+    pub fn threshold(&self) -> u64 {
+        self.threshold.max(1)
+    }
+}
+
+// Approvals from principals that are no longer custodians don't count.
+fn approve(state: &mut State, id: u64, caller: Principal) -> Result<Proposal> {
+    let now = api::time();
+    let threshold = state.proposal_threshold();
+    let proposals = state.proposals.get_or_insert_with(Default::default);
+    let proposal = proposals
+        .proposals
+        .get_mut(id as usize)
+        .ok_or(Error::InvalidProposalId)?;
+    if !matches!(proposal.status, ProposalStatus::Open) {
+        return Err(Error::Other);
+    }
+    if now >= proposal.expires_at {
+        proposal.status = ProposalStatus::Expired;
+        return Ok(proposal.clone());
+    }
+    if !proposal.approvals.contains(&caller) {
+        proposal.approvals.push(caller);
+    }
+    let approvals = proposal.approvals.clone();
+    let action = proposal.action.clone();
+    let approved = approvals
+        .iter()
+        .filter(|&approver| state.custodians.contains(approver))
+        .count() as u64;
+    if approved >= threshold {
+        let status = match execute(state, action) {
+            Ok(()) => ProposalStatus::Executed,
+            Err(e) => ProposalStatus::Failed(e),
+        };
+        state.proposals.as_mut().unwrap().proposals[id as usize].status = status;
+    }
+    Ok(state.proposals.as_ref().unwrap().proposals[id as usize].clone())
+}
+
+fn execute(state: &mut State, action: ProposalAction) -> Result<()> {
+    match action {
+        ProposalAction::SetCustodian { user, custodian } => {
+            set_role(state, user, Role::Admin, custodian)?
+        }
+        ProposalAction::SetRole {
+            user,
+            role,
+            granted,
+        } => set_role(state, user, role, granted)?,
+        ProposalAction::ForceTransfer { token_id, to } => {
+            if to == MGMT {
+                return Err(Error::ZeroAddress);
+            }
+            let from = state.live_nft(token_id)?.owner;
+            state.nfts[token_id as usize].owner = to;
+            state.set_approved(token_id, None);
+            state.move_token(token_id, Some(from), to);
+            crate::http::update_owner(state, token_id);
+            state.record_tx(TransactionType::TransferFrom { token_id, from, to });
+        }
+        ProposalAction::SetMintPhases(phases) => state.set_phases(phases)?,
+        ProposalAction::SetDefaultMintLimit(limit) => state.default_mint_limit = limit,
+        ProposalAction::AddToWhiteList(entries) => {
+            for entry in entries {
+                state.white_list.insert(entry.principal, entry.max_mint);
+            }
+        }
+        ProposalAction::RemoveFromWhiteList(users) => {
+            for user in users {
+                state.white_list.remove(&user);
+            }
+        }
+        ProposalAction::ClearWhiteList => state.white_list.clear(),
+        ProposalAction::SetPurgeBurned(purge) => state.purge_burned = Some(purge),
+        ProposalAction::SetThreshold(threshold) => {
+            if threshold == 0 || threshold > state.custodians.len() as u64 {
+                return Err(Error::Other);
+            }
+            state
+                .proposals
+                .get_or_insert_with(Default::default)
+                .threshold = threshold;
+        }
+    }
+    Ok(())
+}
+
+fn set_role(state: &mut State, user: Principal, role: Role, granted: bool) -> Result<()> {
+    // the custodians left must still be able to reach the threshold
+    if role == Role::Admin
+        && !granted
+        && state.custodians.contains(&user)
+        && (state.custodians.len() as u64) <= state.proposal_threshold()
+    {
+        return Err(Error::LastAdmin);
+    }
+    state.set_role(user, role, granted)
+}
+
+// The proposer's approval is counted right away. `expires_at` is in nanoseconds, and defaults to a week from now.
+#[update]
+fn propose(action: ProposalAction, expires_at: Option<u64>) -> Result<Proposal> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let caller = api::caller();
+        if !state.custodians.contains(&caller) {
+            return Err(Error::Unauthorized);
+        }
+        let now = api::time();
+        let proposals = state.proposals.get_or_insert_with(Default::default);
+        let id = proposals.proposals.len() as u64;
+        proposals.proposals.push(Proposal {
+            id,
+            proposer: caller,
+            action,
+            approvals: vec![],
+            created_at: now,
+            expires_at: expires_at.unwrap_or(now + DEFAULT_LIFETIME),
+            status: ProposalStatus::Open,
+        });
+        approve(&mut state, id, caller)
+    })
+}
+
+#[update]
+fn approve_proposal(id: u64) -> Result<Proposal> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let caller = api::caller();
+        if !state.custodians.contains(&caller) {
+            return Err(Error::Unauthorized);
+        }
+        approve(&mut state, id, caller)
+    })
+}
+
+#[query]
+fn proposal(id: u64) -> Option<Proposal> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        state
+            .proposals
+            .as_ref()?
+            .proposals
+            .get(id as usize)
+            .cloned()
+    })
+}
+
+// Proposals are only marked expired when someone tries to approve them, so check `expires_at` too.
+#[query]
+fn proposals(start: u64, limit: u64) -> Vec<Proposal> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        state
+            .proposals
+            .iter()
+            .flat_map(|proposals| &proposals.proposals)
+            .skip(start as usize)
+            .take(limit.min(MAX_PROPOSALS_PER_QUERY) as usize)
+            .cloned()
+            .collect()
+    })
+}
+
+#[query]
+fn proposal_threshold() -> u64 {
+    STATE.with(|state| state.borrow().proposal_threshold())
+}